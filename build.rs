@@ -1,4 +1,13 @@
 fn main() {
     pkg_config::probe_library("libpulse-simple").unwrap();
     pkg_config::probe_library("opus").unwrap();
+    if std::env::var_os("CARGO_FEATURE_ALSA").is_some() {
+        pkg_config::probe_library("alsa").unwrap();
+    }
+    if std::env::var_os("CARGO_FEATURE_PIPEWIRE").is_some() {
+        pkg_config::probe_library("libpipewire-0.3").unwrap();
+    }
+    if std::env::var_os("CARGO_FEATURE_JACK").is_some() {
+        pkg_config::probe_library("jack").unwrap();
+    }
 }
@@ -23,6 +23,9 @@ pkgs.mkShell rec {
   nativeBuildInputs = with pkgs; [
     libopus
     libpulseaudio
+    alsa-lib
+    pipewire
+    libjack2
   ];
 
   shellHook =
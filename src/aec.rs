@@ -0,0 +1,355 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+
+use log::debug;
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+
+use crate::AudioFrame;
+use crate::vad::energy_db;
+
+// 10ms at 48kHz, every supported frame duration is a multiple of this
+const BLOCK: usize = 480;
+const FFT_SIZE: usize = BLOCK * 2;
+const BINS: usize = FFT_SIZE / 2 + 1;
+// length of the echo tail the filter can model, 120ms covers laptop speakers in a normal room
+const PARTITIONS: usize = 12;
+// the furthest the echo may lag behind what we handed to the playback stream
+const MAX_DELAY_BLOCKS: usize = 50;
+// how much capture history the delay is estimated from, and how often
+const ESTIMATION_BLOCKS: usize = 300;
+const ESTIMATE_EVERY: usize = 50;
+// envelope correlation needed before we trust a delay estimate
+const MIN_CORRELATION: f32 = 0.4;
+// the far end has to vary at least this much to give the envelopes something to correlate
+const MIN_FAR_SPREAD_DB: f32 = 6.0;
+// below this the far end counts as silent and the filter is not adapted
+const FAR_ACTIVE_DB: f32 = -55.0;
+const STEP_SIZE: f32 = 0.3;
+// keeps the normalisation from blowing up the update in bins without far end energy
+const REGULARISATION: f32 = 1e-4;
+// blocks the filter stays frozen after the near end talked over the far end
+const DOUBLE_TALK_HOLD: usize = 3;
+
+/// Removes what the speakers played from the microphone signal.
+///
+/// The far end reference is the mix `play_audio` hands to the playback backend. Its delay relative to
+/// the capture stream is estimated by correlating the energy envelopes of both, the echo path
+/// itself is learned by a partitioned block frequency domain NLMS filter per capture channel.
+pub struct EchoCanceller {
+    reference_rx: Receiver<AudioFrame>,
+    reference: VecDeque<f32>,
+    channels: Vec<ChannelFilter>,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    // spectra of the aligned far end, newest first, one per partition
+    far_spectra: VecDeque<Vec<Complex<f32>>>,
+    far_power: Vec<f32>,
+    delay_blocks: usize,
+    // energy envelope of the far end per block, and how many blocks were ever seen
+    far_levels: VecDeque<f32>,
+    far_blocks: usize,
+    far_partial: Vec<f32>,
+    // energy of every capture block with the far block count at the time it was captured
+    near_levels: VecDeque<(f32, usize)>,
+    blocks_since_estimate: usize,
+    time: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    block: Vec<f32>,
+}
+
+struct ChannelFilter {
+    weights: Vec<Vec<Complex<f32>>>,
+    double_talk: usize,
+}
+
+impl ChannelFilter {
+    fn new() -> Self {
+        ChannelFilter {
+            weights: vec![vec![Complex::default(); BINS]; PARTITIONS],
+            double_talk: 0,
+        }
+    }
+}
+
+impl EchoCanceller {
+    pub fn new(channels: usize, reference_rx: Receiver<AudioFrame>) -> Self {
+        let mut planner = RealFftPlanner::<f32>::new();
+        EchoCanceller {
+            reference_rx,
+            reference: VecDeque::new(),
+            channels: (0..channels).map(|_| ChannelFilter::new()).collect(),
+            forward: planner.plan_fft_forward(FFT_SIZE),
+            inverse: planner.plan_fft_inverse(FFT_SIZE),
+            far_spectra: (0..PARTITIONS)
+                .map(|_| vec![Complex::default(); BINS])
+                .collect(),
+            far_power: vec![0.0; BINS],
+            delay_blocks: 0,
+            far_levels: VecDeque::new(),
+            far_blocks: 0,
+            far_partial: Vec::with_capacity(BLOCK),
+            near_levels: VecDeque::new(),
+            blocks_since_estimate: 0,
+            time: vec![0.0; FFT_SIZE],
+            spectrum: vec![Complex::default(); BINS],
+            block: vec![0.0; BLOCK],
+        }
+    }
+
+    /// Moves the far end audio played since the last call into the history. Has to be called
+    /// regularly even while nothing gets processed, otherwise the reference piles up.
+    pub fn update_reference(&mut self) {
+        while let Ok(frame) = self.reference_rx.try_recv() {
+            for &s in frame.samples() {
+                self.reference.push_back(s);
+                self.far_partial.push(s);
+                if self.far_partial.len() == BLOCK {
+                    self.far_levels.push_back(energy_db(&self.far_partial));
+                    self.far_blocks += 1;
+                    self.far_partial.clear();
+                }
+            }
+        }
+        let max_reference = (MAX_DELAY_BLOCKS + PARTITIONS + 8) * BLOCK;
+        while self.reference.len() > max_reference {
+            self.reference.pop_front();
+        }
+        while self.far_levels.len() > ESTIMATION_BLOCKS + MAX_DELAY_BLOCKS + 8 {
+            self.far_levels.pop_front();
+        }
+    }
+
+    /// Cancels the echo in an interleaved frame in place, its length must be a multiple of 10ms
+    pub fn process(&mut self, frame: &mut AudioFrame) {
+        self.update_reference();
+        let pcm = frame.samples_mut();
+        let channels = self.channels.len();
+        let blocks = pcm.len() / (BLOCK * channels);
+        for (b, chunk) in pcm.chunks_mut(BLOCK * channels).enumerate() {
+            // later blocks of the frame were captured more recently
+            let age = blocks - 1 - b;
+            self.track_delay(energy_db(chunk), age);
+            let reference_end = self
+                .reference
+                .len()
+                .checked_sub((self.delay_blocks + age) * BLOCK);
+            let far_active = self.push_far_spectrum(reference_end);
+            let far_peak = self.far_peak(reference_end);
+            for c in 0..channels {
+                for (i, s) in chunk.iter().skip(c).step_by(channels).enumerate() {
+                    self.block[i] = *s;
+                }
+                self.process_block(c, far_active, far_peak);
+                for (i, s) in chunk.iter_mut().skip(c).step_by(channels).enumerate() {
+                    *s = self.block[i];
+                }
+            }
+        }
+    }
+
+    /// Transforms the two reference blocks ending at `end`, returns whether the far end talks
+    fn push_far_spectrum(&mut self, end: Option<usize>) -> bool {
+        self.time.fill(0.0);
+        if let Some(end) = end {
+            let start = end.saturating_sub(FFT_SIZE);
+            let offset = FFT_SIZE - (end - start);
+            for (i, s) in self.reference.range(start..end).enumerate() {
+                self.time[offset + i] = *s;
+            }
+        }
+        let level = energy_db(&self.time[BLOCK..]);
+        let mut spectrum = self.far_spectra.pop_back().unwrap_or_default();
+        spectrum.resize(BINS, Complex::default());
+        if self.forward.process(&mut self.time, &mut spectrum).is_err() {
+            spectrum.fill(Complex::default());
+        }
+        for (power, bin) in self.far_power.iter_mut().zip(&spectrum) {
+            *power = 0.9 * *power + 0.1 * bin.norm_sqr();
+        }
+        self.far_spectra.push_front(spectrum);
+        level > FAR_ACTIVE_DB
+    }
+
+    /// Loudest far end sample that can still echo in the current block
+    fn far_peak(&self, end: Option<usize>) -> f32 {
+        let Some(end) = end else {
+            return 0.0;
+        };
+        let start = end.saturating_sub(PARTITIONS * BLOCK);
+        self.reference
+            .range(start..end)
+            .fold(0.0, |peak: f32, s| peak.max(s.abs()))
+    }
+
+    fn process_block(&mut self, c: usize, far_active: bool, far_peak: f32) {
+        let filter = &mut self.channels[c];
+
+        // echo estimate, overlap-save keeps the second half of the circular convolution
+        self.spectrum.fill(Complex::default());
+        for (weights, far) in filter.weights.iter().zip(&self.far_spectra) {
+            for ((out, w), x) in self.spectrum.iter_mut().zip(weights).zip(far) {
+                *out += w * x;
+            }
+        }
+        clear_imaginary_edges(&mut self.spectrum);
+        if self
+            .inverse
+            .process(&mut self.spectrum, &mut self.time)
+            .is_err()
+        {
+            return;
+        }
+        let scale = 1.0 / FFT_SIZE as f32;
+        let near_power: f32 = self.block.iter().map(|s| s * s).sum();
+        let near_peak = self
+            .block
+            .iter()
+            .fold(0.0, |peak: f32, s| peak.max(s.abs()));
+        let mut error_power = 0.0;
+        for (i, near) in self.block.iter().enumerate() {
+            let error = near - self.time[BLOCK + i] * scale;
+            error_power += error * error;
+            self.time[BLOCK + i] = error;
+        }
+        self.time[..BLOCK].fill(0.0);
+
+        // the near end talking over the echo would make the filter learn their voice
+        if near_peak > far_peak {
+            filter.double_talk = DOUBLE_TALK_HOLD;
+        } else {
+            filter.double_talk = filter.double_talk.saturating_sub(1);
+        }
+        // a filter that adds energy is still converging or went wrong, don't make it worse
+        if error_power <= near_power {
+            self.block.copy_from_slice(&self.time[BLOCK..]);
+        }
+        if !far_active || filter.double_talk > 0 {
+            return;
+        }
+
+        if self
+            .forward
+            .process(&mut self.time, &mut self.spectrum)
+            .is_err()
+        {
+            return;
+        }
+        let error_spectrum = self.spectrum.clone();
+        for (weights, far) in filter.weights.iter_mut().zip(&self.far_spectra) {
+            for (k, bin) in self.spectrum.iter_mut().enumerate() {
+                let norm = PARTITIONS as f32 * self.far_power[k] + REGULARISATION;
+                *bin = far[k].conj() * error_spectrum[k] * (STEP_SIZE / norm);
+            }
+            // drop the circular part of the gradient so the filter stays a linear convolution
+            clear_imaginary_edges(&mut self.spectrum);
+            if self
+                .inverse
+                .process(&mut self.spectrum, &mut self.time)
+                .is_err()
+            {
+                return;
+            }
+            for s in self.time[..BLOCK].iter_mut() {
+                *s *= scale;
+            }
+            self.time[BLOCK..].fill(0.0);
+            if self
+                .forward
+                .process(&mut self.time, &mut self.spectrum)
+                .is_err()
+            {
+                return;
+            }
+            for (w, g) in weights.iter_mut().zip(&self.spectrum) {
+                *w += g;
+            }
+        }
+    }
+
+    /// Records the capture envelope and every now and then looks for the lag at which it
+    /// matches the far end envelope best
+    fn track_delay(&mut self, near_level: f32, age: usize) {
+        self.near_levels
+            .push_back((near_level, self.far_blocks.saturating_sub(age)));
+        if self.near_levels.len() > ESTIMATION_BLOCKS {
+            self.near_levels.pop_front();
+        }
+        self.blocks_since_estimate += 1;
+        if self.blocks_since_estimate < ESTIMATE_EVERY || self.near_levels.len() < ESTIMATION_BLOCKS
+        {
+            return;
+        }
+        self.blocks_since_estimate = 0;
+
+        let first_far = self.far_blocks - self.far_levels.len();
+        let mut best: Option<(usize, f32)> = None;
+        for lag in 0..=MAX_DELAY_BLOCKS {
+            let pairs: Vec<(f32, f32)> = self
+                .near_levels
+                .iter()
+                .filter_map(|&(near, far_count)| {
+                    let index = far_count.checked_sub(lag + 1)?.checked_sub(first_far)?;
+                    self.far_levels.get(index).map(|&far| (near, far))
+                })
+                .collect();
+            if pairs.len() < ESTIMATION_BLOCKS / 2 {
+                continue;
+            }
+            let (min, max) = pairs
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(min, max), &(_, far)| {
+                    (min.min(far), max.max(far))
+                });
+            if max - min < MIN_FAR_SPREAD_DB {
+                continue;
+            }
+            let correlation = correlation(&pairs);
+            if best.is_none_or(|(_, c)| correlation > c) {
+                best = Some((lag, correlation));
+            }
+        }
+
+        let Some((lag, correlation)) = best else {
+            return;
+        };
+        // start the filter a block early, the two streams drift against each other a bit
+        let delay = lag.saturating_sub(1);
+        if correlation >= MIN_CORRELATION && delay != self.delay_blocks {
+            debug!(
+                "Echo delay changed to {} ms (correlation {:.2})",
+                delay * 10,
+                correlation
+            );
+            self.delay_blocks = delay;
+            for filter in self.channels.iter_mut() {
+                *filter = ChannelFilter::new();
+            }
+        }
+    }
+}
+
+/// The DC and nyquist bins have to stay real for the inverse transform
+fn clear_imaginary_edges(spectrum: &mut [Complex<f32>]) {
+    spectrum[0].im = 0.0;
+    spectrum[BINS - 1].im = 0.0;
+}
+
+/// Pearson correlation between the two halves of the pairs
+fn correlation(pairs: &[(f32, f32)]) -> f32 {
+    let n = pairs.len() as f32;
+    let (mean_a, mean_b) = pairs
+        .iter()
+        .fold((0.0, 0.0), |(a, b), &(x, y)| (a + x / n, b + y / n));
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for &(x, y) in pairs {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    if var_a <= 0.0 || var_b <= 0.0 {
+        return 0.0;
+    }
+    cov / (var_a * var_b).sqrt()
+}
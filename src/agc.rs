@@ -0,0 +1,159 @@
+use log::debug;
+
+use crate::vad::energy_db;
+use crate::{AudioFrame, ErrorKind};
+
+// highest sample level the limiter lets through, leaves the encoder a bit of headroom
+const LIMIT: f32 = 0.89;
+const LIMITER_RELEASE_MS: f32 = 50.0;
+// frames this far below the tracked speech level are pauses or hangover, not speech
+const SPEECH_RANGE_DB: f32 = 20.0;
+// raising the gain slowly keeps the background from pumping up between words,
+// lowering it quickly avoids hitting the limiter for long
+const GAIN_RISE_DB_PER_SEC: f32 = 3.0;
+const GAIN_FALL_DB_PER_SEC: f32 = 30.0;
+const MIN_GAIN_DB: f32 = -20.0;
+
+#[derive(Debug, Clone)]
+pub struct GainSettings {
+    /// Adjust the gain to reach `target_db`, otherwise `manual_gain_db` is used
+    pub automatic: bool,
+    /// Loudness of speech the automatic gain aims for, in dBFS
+    pub target_db: f32,
+    pub max_gain_db: f32,
+    pub manual_gain_db: f32,
+}
+
+impl Default for GainSettings {
+    fn default() -> Self {
+        GainSettings {
+            automatic: true,
+            target_db: -20.0,
+            max_gain_db: 30.0,
+            manual_gain_db: 0.0,
+        }
+    }
+}
+
+impl GainSettings {
+    pub fn set_target(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.target_db = parse_db(value, -40.0, -6.0, "agc target")?;
+        Ok(())
+    }
+
+    pub fn set_max_gain(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.max_gain_db = parse_db(value, 0.0, 40.0, "agc max gain")?;
+        Ok(())
+    }
+
+    pub fn set_manual_gain(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.manual_gain_db = parse_db(value, -40.0, 40.0, "input gain")?;
+        Ok(())
+    }
+}
+
+/// Brings the microphone to a common loudness and keeps peaks away from full scale
+pub struct GainControl {
+    settings: GainSettings,
+    frame_ms: f32,
+    gain_db: f32,
+    // linear gain the previous frame ended with, the next one ramps from there
+    applied: f32,
+    speech_level_db: Option<f32>,
+    limiter_envelope: f32,
+    limiter_release: f32,
+}
+
+impl GainControl {
+    pub fn new(settings: GainSettings, frame_ms: u8) -> Self {
+        let gain_db = if settings.automatic {
+            0.0
+        } else {
+            settings.manual_gain_db
+        };
+        let release_samples = LIMITER_RELEASE_MS * crate::SAMPLE_RATE as f32 / 1000.0;
+        GainControl {
+            settings,
+            frame_ms: frame_ms as f32,
+            gain_db,
+            applied: db_to_linear(gain_db),
+            speech_level_db: None,
+            limiter_envelope: 0.0,
+            limiter_release: (-1.0 / release_samples).exp(),
+        }
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        self.gain_db
+    }
+
+    /// Applies the gain and the limiter to an interleaved frame. `speech` tells whether the
+    /// frame should count towards the loudness the automatic gain adjusts to.
+    pub fn process(&mut self, frame: &mut AudioFrame, speech: bool) {
+        if self.settings.automatic && speech {
+            self.adapt(energy_db(frame.samples()));
+        }
+        let pcm = frame.samples_mut();
+
+        let target = db_to_linear(self.gain_db);
+        let step = (target - self.applied) / pcm.len().max(1) as f32;
+        for s in pcm.iter_mut() {
+            self.applied += step;
+            *s *= self.applied;
+
+            let magnitude = s.abs();
+            self.limiter_envelope = if magnitude > self.limiter_envelope {
+                magnitude
+            } else {
+                self.limiter_envelope * self.limiter_release
+            };
+            if self.limiter_envelope > LIMIT {
+                *s *= LIMIT / self.limiter_envelope;
+            }
+        }
+        self.applied = target;
+    }
+
+    fn adapt(&mut self, level_db: f32) {
+        let speech_level = match self.speech_level_db {
+            Some(speech_level) if level_db < speech_level - SPEECH_RANGE_DB => return,
+            // follow louder speech quickly so we don't clip, quieter speech slowly
+            Some(speech_level) if level_db > speech_level => {
+                speech_level + 0.3 * (level_db - speech_level)
+            }
+            Some(speech_level) => speech_level + 0.05 * (level_db - speech_level),
+            None => level_db,
+        };
+        self.speech_level_db = Some(speech_level);
+
+        let wanted =
+            (self.settings.target_db - speech_level).clamp(MIN_GAIN_DB, self.settings.max_gain_db);
+        let rise = GAIN_RISE_DB_PER_SEC * self.frame_ms / 1000.0;
+        let fall = GAIN_FALL_DB_PER_SEC * self.frame_ms / 1000.0;
+        let gain_db = self.gain_db + (wanted - self.gain_db).clamp(-fall, rise);
+        if gain_db.round() != self.gain_db.round() {
+            debug!(
+                "Input gain {:.1} dB, speech at {:.1} dBFS",
+                gain_db, speech_level
+            );
+        }
+        self.gain_db = gain_db;
+    }
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn parse_db(value: &str, min: f32, max: f32, name: &str) -> Result<f32, ErrorKind> {
+    value
+        .parse::<f32>()
+        .ok()
+        .filter(|db| (min..=max).contains(db))
+        .ok_or_else(|| {
+            ErrorKind::ConfigError(format!(
+                "{} must be between {} and {} dB, got {}",
+                name, min, max, value
+            ))
+        })
+}
@@ -1,135 +1,723 @@
 use std::{
-    slice,
-    sync::mpsc::{Receiver, Sender},
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError},
     thread::sleep,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
-use log::{debug, error};
-use opus::{Channels, Decoder, Encoder};
+use log::{debug, error, info};
+use opus::{Channels, Decoder};
 
 use crate::{
-    AudioProducer, BUF_SIZE, CHANNELS, Consumer, FRAME_SIZE, SAMPLE_RATE,
+    AudioFrame, AudioProducer, BUF_SIZE, CHANNELS, Consumer, FRAME_SIZE, SAMPLE_RATE,
+    aec::EchoCanceller,
+    agc::{GainControl, GainSettings},
     client::ClientMessage,
-    implementations::pulseaudio::{PulseAudioConsumer, PulseAudioProducer}, server::AudioData,
+    codec::{self, CodecProfile, Encoder},
+    denoise::{NoiseSuppressionSettings, NoiseSuppressor},
+    jitter::{JitterBuffer, JitterStats, Playout},
+    mixer::Mixer,
+    server::{AudioData, ReceiverReport, SHARE_STREAM, VOICE_STREAM},
+    vad::{VadSettings, VoiceActivityDetector},
 };
-use opus::Application::Voip;
 
+// how long to listen to the room before deciding what counts as silence
+const CALIBRATION_MS: usize = 1000;
+// shared audio below one 16 bit step is not sent, players output digital silence between
+// tracks and while paused
+const SHARE_SILENCE: f32 = 1.0 / i16::MAX as f32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransmitMode {
+    VoiceActivity,
+    PushToTalk,
+}
+
+#[derive(Debug)]
+pub struct CaptureSettings {
+    pub codec: CodecProfile,
+    pub vad: VadSettings,
+    pub noise_suppression: NoiseSuppressionSettings,
+    /// Remove what the speakers play from the microphone signal
+    pub echo_cancellation: bool,
+    pub gain: GainSettings,
+    pub mode: TransmitMode,
+    /// How long to keep sending after the push-to-talk key was released
+    pub ptt_tail: Duration,
+}
+
+/// Encodes captured frames and hands them to the coordinator as numbered packets of one
+/// stream
+struct Packetizer {
+    encoder: Encoder,
+    encoded_data: [u8; BUF_SIZE as usize],
+    sequence_number: u32,
+    stream_id: u8,
+    channels: u8,
+    frame_ms: u8,
+    // loss the listeners of this stream reported and when
+    loss_reports: HashMap<SocketAddr, (u8, Instant)>,
+    packet_loss: u8,
+}
+
+impl Packetizer {
+    fn new(profile: &CodecProfile, stream_id: u8) -> Self {
+        Packetizer {
+            encoder: Encoder::new(profile).unwrap(),
+            encoded_data: [0u8; BUF_SIZE as usize],
+            sequence_number: 0,
+            stream_id,
+            channels: profile.channels as u8,
+            frame_ms: profile.frame_ms,
+            loss_reports: HashMap::new(),
+            packet_loss: 0,
+        }
+    }
+
+    fn report(&mut self, addr: SocketAddr, report: &ReceiverReport) {
+        self.loss_reports
+            .insert(addr, (report.loss_percent, Instant::now()));
+    }
+
+    /// Tunes the error correction for the worst listener, forgetting those that stopped
+    /// reporting
+    fn adapt_to_loss(&mut self) {
+        self.loss_reports
+            .retain(|_, (_, received)| received.elapsed() < REPORT_TIMEOUT);
+        let worst_loss = self
+            .loss_reports
+            .values()
+            .map(|(loss, _)| *loss)
+            .max()
+            .unwrap_or(0);
+        if worst_loss != self.packet_loss {
+            debug!(
+                "Expected packet loss of stream {} changed to {}%",
+                self.stream_id, worst_loss
+            );
+            match self.encoder.set_packet_loss_perc(worst_loss as i32) {
+                Ok(_) => self.packet_loss = worst_loss,
+                Err(e) => error!("Error setting expected packet loss: {:?}", e),
+            }
+        }
+    }
+
+    /// Encodes and sends `frame`, stamped with when it was `captured`
+    fn send(&mut self, frame: &AudioFrame, captured: SystemTime, tx: &Sender<ClientMessage>) {
+        let n = match self.encoder.encode(frame, &mut self.encoded_data) {
+            Ok(n) => n,
+            Err(e) => {
+                error!("Error encoding frame: {:?}", e);
+                return;
+            }
+        };
+        debug!("Encoded {} samples to {} bytes", frame.samples().len(), n);
+        let timestamp = captured
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        let _ = tx.send(ClientMessage::Audio(AudioData {
+            stream_id: self.stream_id,
+            timestamp,
+            seq_number: self.sequence_number,
+            channels: self.channels,
+            frame_ms: self.frame_ms,
+            data: self.encoded_data[..n].to_vec(),
+        }));
+    }
+}
+
+/// Captures, processes and encodes audio until `rx` closes. When reading fails the
+/// producer is dropped and `InputFailed` sent, recording continues with the next
+/// `NewProducer`.
 pub fn record_audio(
     tx: Sender<ClientMessage>,
-    producer: &mut PulseAudioProducer,
+    mut producer: Box<dyn AudioProducer + Send>,
     rx: Receiver<ClientMessage>,
+    echo_reference: Receiver<AudioFrame>,
+    settings: CaptureSettings,
 ) {
-    let mut data = vec![0u8; BUF_SIZE as usize];
-    let mut encoded_data = [0u8; BUF_SIZE as usize];
-    let mut encoder = opus_encoder();
-    let mut hangover = 0;
+    let profile = &settings.codec;
+    let mut frame = AudioFrame::new(profile.channels, SAMPLE_RATE, profile.frame_size());
+    let mut packetizer = Packetizer::new(profile, VOICE_STREAM);
+    let mut vad =
+        VoiceActivityDetector::new(settings.vad.clone(), profile.channels, profile.frame_ms);
+    // frames captured while the detector was still deciding, sent once it opens
+    // with the time each one was captured
+    let mut pre_roll: VecDeque<(SystemTime, AudioFrame)> = VecDeque::new();
+    let mut denoiser = NoiseSuppressor::new(profile.channels, settings.noise_suppression.strength);
+    let mut denoise = settings.noise_suppression.enabled;
+    let mut echo_canceller = settings
+        .echo_cancellation
+        .then(|| EchoCanceller::new(profile.channels, echo_reference));
+    let mut gain = GainControl::new(settings.gain.clone(), profile.frame_ms);
+    let mut shown_gain_db: Option<f32> = None;
     let mut muted = false;
-    let hangover_limit = 10;
-    let mut sequence_number: u32 = 0;
-    let mut timestamp: u64 = 0;
+    let mut ptt_pressed = false;
+    let mut ptt_released_at: Option<Instant> = None;
+    let mut last_stats = Instant::now();
+
+    if settings.mode == TransmitMode::VoiceActivity && settings.vad.calibrate {
+        let frames = CALIBRATION_MS / profile.frame_ms as usize;
+        let mut recorded = Vec::with_capacity(frames);
+        for _ in 0..frames {
+            if producer.produce(&mut frame).is_err() {
+                break;
+            }
+            if let Some(echo_canceller) = echo_canceller.as_mut() {
+                echo_canceller.process(&mut frame);
+            }
+            if denoise {
+                denoiser.process(&mut frame);
+            }
+            recorded.push(frame.clone());
+        }
+        vad.calibrate(&recorded);
+        info!("Noise floor is {:.1} dBFS", vad.noise_floor_db());
+    }
+
+    let mut producer = Some(producer);
+
     loop {
-        match rx.try_recv() {
-            Ok(ClientMessage::ToggleMute) => {
-                debug!("Got toggle mute in record_audio");
-                muted = !muted;
+        loop {
+            let msg = match rx.try_recv() {
+                Ok(msg) => msg,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            };
+            match msg {
+                ClientMessage::ToggleMute => {
+                    debug!("Got toggle mute in record_audio");
+                    muted = !muted;
+                }
+                ClientMessage::ToggleNoiseSuppression => {
+                    denoise = !denoise;
+                    denoiser.reset();
+                }
+                ClientMessage::PttPress => {
+                    ptt_pressed = true;
+                    ptt_released_at = None;
+                }
+                ClientMessage::PttRelease => {
+                    if ptt_pressed {
+                        ptt_released_at = Some(Instant::now());
+                    }
+                    ptt_pressed = false;
+                }
+                ClientMessage::RecvReport(addr, report) => packetizer.report(addr, &report),
+                ClientMessage::NewProducer(new_producer) => {
+                    info!("Switched input device");
+                    // the old device goes away here, the encoder and detector carry on
+                    producer = Some(new_producer);
+                    denoiser.reset();
+                }
+                _ => {}
             }
-            _ => {}
         }
-        match producer.produce(&mut data) {
-            Ok(_) => {}
-            Err(_) => {
-                error!("Error reading from stream");
-                break;
+        if last_stats.elapsed() >= Duration::from_secs(5) {
+            if let Some(latency) = producer.as_ref().and_then(|producer| producer.latency()) {
+                debug!("Input latency {} ms", latency.as_millis());
+                let _ = tx.send(ClientMessage::DeviceLatency {
+                    input: Some(latency),
+                    output: None,
+                });
+            }
+            last_stats = Instant::now();
+        }
+        packetizer.adapt_to_loss();
+        let Some(input) = producer.as_mut() else {
+            // the coordinator is trying to reopen the device
+            if let Some(echo_canceller) = echo_canceller.as_mut() {
+                echo_canceller.update_reference();
             }
+            sleep(Duration::from_millis(20));
+            continue;
+        };
+        if let Err(e) = input.produce(&mut frame) {
+            error!("Error reading from stream: {:?}", e);
+            producer = None;
+            pre_roll.clear();
+            let _ = tx.send(ClientMessage::TransmitAudio(false));
+            let _ = tx.send(ClientMessage::InputFailed(e));
+            continue;
         }
+        let captured = SystemTime::now();
         if muted {
+            if let Some(echo_canceller) = echo_canceller.as_mut() {
+                echo_canceller.update_reference();
+            }
             sleep(Duration::from_millis(20));
             continue;
         }
-        let pcm: &[i16] =
-            unsafe { slice::from_raw_parts(data.as_ptr() as *const i16, data.len() / 2) };
-
-        let samples_needed = FRAME_SIZE * CHANNELS;
-        let pcm = &pcm[..samples_needed];
-        if is_silence(pcm, 200.0) {
-            if hangover == 0 {
-                let _ = tx.send(ClientMessage::TransmitAudio(false));
-                continue;
+        // the canceller needs the signal as linear as possible, so it runs before denoising
+        if let Some(echo_canceller) = echo_canceller.as_mut() {
+            echo_canceller.process(&mut frame);
+        }
+        if denoise {
+            denoiser.process(&mut frame);
+        }
+        // the detector works relative to the noise floor, so it runs before the gain changes
+        let speech = vad.process(&frame);
+        let transmit = match settings.mode {
+            TransmitMode::PushToTalk => {
+                ptt_pressed || ptt_released_at.is_some_and(|t| t.elapsed() < settings.ptt_tail)
             }
-            hangover -= 1;
-        } else {
-            hangover = hangover_limit;
+            TransmitMode::VoiceActivity => speech,
+        };
+        gain.process(&mut frame, speech && transmit);
+        if shown_gain_db.is_none_or(|shown| (gain.gain_db() - shown).abs() >= 0.5) {
+            shown_gain_db = Some(gain.gain_db());
+            let _ = tx.send(ClientMessage::InputGain(gain.gain_db()));
+        }
+        let _ = tx.send(ClientMessage::TransmitAudio(transmit));
+        if !transmit {
+            if settings.mode == TransmitMode::VoiceActivity {
+                pre_roll.push_back((captured, frame.clone()));
+                if pre_roll.len() > vad.attack_frames() {
+                    pre_roll.pop_front();
+                }
+            }
+            continue;
         }
         debug!("Acive audio detected, sending packet");
-        let n = encoder.encode(&pcm, &mut encoded_data).unwrap();
-
-        debug!(
-            "Read {} samples, data has {} samples, encoded to {} bytes,",
-            pcm.len(),
-            data.len() / 2,
-            n,
-        );
-        timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
-        sequence_number = sequence_number.wrapping_add(1);
-        let _ = tx.send(ClientMessage::TransmitAudio(true));
-        let _ = tx.send(ClientMessage::Audio(AudioData {
-            timestamp,
-            seq_number: sequence_number,
-            data: encoded_data[..n].to_vec(),
-        }));
+        for (early_captured, early) in pre_roll.drain(..) {
+            packetizer.send(&early, early_captured, &tx);
+        }
+        packetizer.send(&frame, captured, &tx);
     }
 }
 
-pub fn play_audio(rx: Receiver<ClientMessage>, consumer: &mut PulseAudioConsumer) {
-    let mut decoder = opus_decoder();
-    let mut decoded_data = vec![0i16; FRAME_SIZE * CHANNELS];
-    let mut deafened = false;
-    for msg in rx.iter() {
-        match msg {
-            ClientMessage::RecvAudio(_, audio) => {
-                if deafened {
-                    sleep(Duration::from_millis(20));
-                    continue;
+/// Captures and encodes the application audio we share until `rx` closes. It goes out
+/// as it is, none of the microphone processing applies. If reading fails sharing stops
+/// with `ShareFailed`.
+pub fn share_audio(
+    tx: Sender<ClientMessage>,
+    mut producer: Box<dyn AudioProducer + Send>,
+    rx: Receiver<ClientMessage>,
+    profile: CodecProfile,
+) {
+    let mut frame = AudioFrame::new(profile.channels, SAMPLE_RATE, profile.frame_size());
+    let mut packetizer = Packetizer::new(&profile, SHARE_STREAM);
+    let mut paused = false;
+    loop {
+        loop {
+            match rx.try_recv() {
+                Ok(ClientMessage::ToggleShare) => paused = !paused,
+                Ok(ClientMessage::RecvReport(addr, report)) => packetizer.report(addr, &report),
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        packetizer.adapt_to_loss();
+        if let Err(e) = producer.produce(&mut frame) {
+            error!("Error reading shared audio: {:?}", e);
+            let _ = tx.send(ClientMessage::ShareFailed(e));
+            return;
+        }
+        let captured = SystemTime::now();
+        // the device is read while paused too, so resuming doesn't send stale audio
+        if paused || frame.samples().iter().all(|s| s.abs() < SHARE_SILENCE) {
+            continue;
+        }
+        packetizer.send(&frame, captured, &tx);
+    }
+}
+
+// after this many consecutive lost frames concealment would only produce noise
+const MAX_CONCEALED_FRAMES: usize = 5;
+// longest frame a sender may use, sizes the decode buffer
+const MAX_FRAME_MS: u8 = 60;
+// how often every receiver reports the loss rate it sees back to the sender
+const REPORT_INTERVAL: Duration = Duration::from_secs(2);
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct PlaybackSettings {
+    /// Fill gaps in the sequence numbers with opus packet loss concealment instead of silence
+    pub plc: bool,
+}
+
+impl Default for PlaybackSettings {
+    fn default() -> Self {
+        PlaybackSettings { plc: true }
+    }
+}
+
+/// Playback state of a single remote speaker
+struct RemoteStream {
+    decoder: Decoder,
+    jitter: JitterBuffer,
+    // format of the incoming packets, the decoder is rebuilt when the sender changes it
+    channels: usize,
+    frame_ms: u8,
+    // decoded samples in the playback channel layout, waiting to be mixed
+    pending: VecDeque<f32>,
+    // lost frames in a row, reset by every received packet
+    missing_in_row: usize,
+    concealed: u64,
+    recovered: u64,
+    // counters at the time of the last receiver report
+    reported: JitterStats,
+}
+
+impl RemoteStream {
+    fn new(audio: &AudioData, path_jitter_ms: f32) -> Self {
+        let mut jitter = JitterBuffer::new();
+        jitter.set_frame_ms(audio.frame_ms);
+        jitter.set_path_jitter(path_jitter_ms);
+        RemoteStream {
+            decoder: opus_decoder(audio.channels as usize),
+            jitter,
+            channels: audio.channels as usize,
+            frame_ms: audio.frame_ms,
+            pending: VecDeque::new(),
+            missing_in_row: 0,
+            concealed: 0,
+            recovered: 0,
+            reported: JitterStats::default(),
+        }
+    }
+
+    fn push(&mut self, audio: AudioData) {
+        if !playable(&audio) {
+            debug!(
+                "Dropping packet {} with {} channels of {} ms",
+                audio.seq_number, audio.channels, audio.frame_ms
+            );
+            return;
+        }
+        if audio.channels as usize != self.channels {
+            debug!("Sender switched to {} channels", audio.channels);
+            self.channels = audio.channels as usize;
+            self.decoder = opus_decoder(self.channels);
+        }
+        if audio.frame_ms != self.frame_ms {
+            self.frame_ms = audio.frame_ms;
+            self.jitter.set_frame_ms(audio.frame_ms);
+        }
+        self.jitter.push(audio, Instant::now());
+    }
+
+    /// Decodes frames until `samples` output samples are pending or the jitter buffer runs dry.
+    /// Returns false if there is nothing to play.
+    fn fill(
+        &mut self,
+        samples: usize,
+        settings: &PlaybackSettings,
+        decoded: &mut [f32],
+        addr: &SocketAddr,
+    ) -> bool {
+        while self.pending.len() < samples {
+            if !self.decode_next(settings, decoded, addr) {
+                break;
+            }
+        }
+        !self.pending.is_empty()
+    }
+
+    fn decode_next(
+        &mut self,
+        settings: &PlaybackSettings,
+        decoded: &mut [f32],
+        addr: &SocketAddr,
+    ) -> bool {
+        let frame = codec::frame_size(self.frame_ms) * self.channels;
+        match self.jitter.pop() {
+            Playout::Packet(audio) => {
+                self.missing_in_row = 0;
+                match self.decoder.decode_float(&audio.data, decoded, false) {
+                    Ok(samples) => self.push_pcm(&decoded[..samples * self.channels]),
+                    Err(e) => {
+                        error!(
+                            "Error decoding packet {} from {}: {:?}",
+                            audio.seq_number, addr, e
+                        );
+                        self.push_silence(frame);
+                    }
+                }
+            }
+            Playout::Missing(seq) => {
+                // the following packet carries a low bitrate copy of the lost one
+                if let Some(next) = self.jitter.peek_next() {
+                    match self
+                        .decoder
+                        .decode_float(&next.data, &mut decoded[..frame], true)
+                    {
+                        Ok(samples) => {
+                            debug!("Recovered packet {} from {} using FEC", seq, addr);
+                            self.missing_in_row = 0;
+                            self.recovered += 1;
+                            self.push_pcm(&decoded[..samples * self.channels]);
+                            return true;
+                        }
+                        Err(e) => error!("Error decoding FEC for packet {}: {:?}", seq, e),
+                    }
                 }
-                let b = decoder.decode(&audio.data, &mut decoded_data, false).unwrap();
-                match consumer.consume(unsafe {
-                    slice::from_raw_parts(
-                        decoded_data.as_ptr() as *const u8,
-                        b * CHANNELS * std::mem::size_of::<i16>(),
-                    )
-                }) {
-                    Ok(_) => {}
+                self.missing_in_row += 1;
+                if !settings.plc || self.missing_in_row > MAX_CONCEALED_FRAMES {
+                    debug!("Packet {} from {} missing, playing silence", seq, addr);
+                    self.push_silence(frame);
+                    return true;
+                }
+                debug!("Packet {} from {} missing, concealing", seq, addr);
+                // an empty packet makes the decoder extrapolate from its previous state
+                match self.decoder.decode_float(&[], &mut decoded[..frame], false) {
+                    Ok(samples) => {
+                        self.concealed += 1;
+                        self.push_pcm(&decoded[..samples * self.channels]);
+                    }
                     Err(e) => {
-                        error!("Error consuming data: {:?}", e);
+                        error!("Error concealing packet {} from {}: {:?}", seq, addr, e);
+                        self.push_silence(frame);
                     }
                 }
             }
-            ClientMessage::ToggleDeafen => {
+            Playout::Empty => return false,
+        }
+        true
+    }
+
+    /// Queues decoded samples, converting them to the playback channel layout
+    fn push_pcm(&mut self, pcm: &[f32]) {
+        if self.channels == CHANNELS {
+            self.pending.extend(pcm);
+        } else {
+            for &s in pcm.iter().step_by(self.channels) {
+                self.pending.extend(std::iter::repeat_n(s, CHANNELS));
+            }
+        }
+    }
+
+    fn push_silence(&mut self, samples: usize) {
+        let samples = samples / self.channels * CHANNELS;
+        self.pending.extend(std::iter::repeat_n(0.0, samples));
+    }
+
+    /// Fills `out` with pending samples, padding with silence
+    fn take(&mut self, out: &mut AudioFrame) {
+        let out = out.samples_mut();
+        let available = out.len().min(self.pending.len());
+        for (out, s) in out.iter_mut().zip(self.pending.drain(..available)) {
+            *out = s;
+        }
+        out[available..].fill(0.0);
+    }
+
+    /// Percentage of packets lost on the network since the last call, None if nothing arrived
+    fn take_loss_percent(&mut self) -> Option<u8> {
+        let stats = self.jitter.stats();
+        let last = std::mem::replace(&mut self.reported, stats);
+        let lost = stats.lost - last.lost;
+        let arrived = (stats.received - last.received)
+            .saturating_sub(stats.duplicate - last.duplicate)
+            .saturating_sub(stats.late - last.late);
+        let expected = arrived + lost;
+        if expected == 0 {
+            return None;
+        }
+        Some((lost * 100 / expected) as u8)
+    }
+}
+
+/// Decodes and mixes what the others send until `rx` closes, every stream of every sender
+/// on its own. When writing fails the consumer is dropped and `OutputFailed` sent, the
+/// streams keep being mixed and thrown away until the next `NewConsumer`.
+pub fn play_audio(
+    tx: Sender<ClientMessage>,
+    rx: Receiver<ClientMessage>,
+    consumer: Box<dyn Consumer + Send>,
+    echo_reference: Sender<AudioFrame>,
+    settings: PlaybackSettings,
+) {
+    let mut streams: HashMap<(SocketAddr, u8), RemoteStream> = HashMap::new();
+    // senders whose shared audio we don't want to hear, their voice still plays
+    let mut muted_shares: HashSet<SocketAddr> = HashSet::new();
+    // half of how much the round trip to the server varies, a guess for the way down to us
+    let mut path_jitter_ms = 0.0;
+    let mut decoded_data = vec![0f32; codec::frame_size(MAX_FRAME_MS) * CHANNELS];
+    let mut frame = AudioFrame::new(CHANNELS, SAMPLE_RATE, FRAME_SIZE);
+    let mut mixer = Mixer::new(CHANNELS, FRAME_SIZE);
+    let mut deafened = false;
+    let frame_duration = frame.duration();
+    let mut next_tick = Instant::now() + frame_duration;
+    let mut last_stats = Instant::now();
+    let mut last_report = Instant::now();
+    let mut consumer = Some(consumer);
+    loop {
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            for ((addr, stream_id), stream) in streams.iter_mut() {
+                if let Some(loss_percent) = stream.take_loss_percent() {
+                    let _ = tx.send(ClientMessage::Report(ReceiverReport {
+                        source: *addr,
+                        stream_id: *stream_id,
+                        loss_percent,
+                    }));
+                }
+            }
+            last_report = Instant::now();
+        }
+        if last_stats.elapsed() >= Duration::from_secs(5) {
+            if let Some(latency) = consumer.as_ref().and_then(|consumer| consumer.latency()) {
+                debug!("Output latency {} ms", latency.as_millis());
+                let _ = tx.send(ClientMessage::DeviceLatency {
+                    input: None,
+                    output: Some(latency),
+                });
+            }
+            for ((addr, stream_id), stream) in &streams {
+                debug!(
+                    "{} stream {}: jitter {:.1} ms, target depth {}, {} recovered, {} concealed, {:?}",
+                    addr,
+                    stream_id,
+                    stream.jitter.jitter_ms(),
+                    stream.jitter.target_depth(),
+                    stream.recovered,
+                    stream.concealed,
+                    stream.jitter.stats()
+                );
+            }
+            last_stats = Instant::now();
+        }
+        let timeout = next_tick.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(ClientMessage::RecvAudio(addr, audio)) => {
+                let muted = audio.stream_id == SHARE_STREAM && muted_shares.contains(&addr);
+                if !playable(&audio) {
+                    debug!(
+                        "Dropping packet from {} with {} channels of {} ms",
+                        addr, audio.channels, audio.frame_ms
+                    );
+                } else if !deafened && !muted {
+                    streams
+                        .entry((addr, audio.stream_id))
+                        .or_insert_with(|| RemoteStream::new(&audio, path_jitter_ms))
+                        .push(audio);
+                }
+            }
+            Ok(ClientMessage::Rtt(stats)) => {
+                path_jitter_ms = stats.variance_ms / 2.0;
+                for stream in streams.values_mut() {
+                    stream.jitter.set_path_jitter(path_jitter_ms);
+                }
+            }
+            Ok(ClientMessage::ToggleShareMute(addr)) => {
+                if !muted_shares.remove(&addr) {
+                    muted_shares.insert(addr);
+                    streams.remove(&(addr, SHARE_STREAM));
+                }
+            }
+            // whoever is still there is announced again after reconnecting
+            Ok(ClientMessage::Disconnect) => {
+                streams.clear();
+                muted_shares.clear();
+            }
+            Ok(ClientMessage::DeleteClient(addr)) => {
+                streams.retain(|(sender, _), _| *sender != addr);
+                muted_shares.remove(&addr);
+            }
+            Ok(ClientMessage::ToggleDeafen) => {
                 deafened = !deafened;
+                for stream in streams.values_mut() {
+                    stream.jitter.clear();
+                    stream.pending.clear();
+                }
+            }
+            Ok(ClientMessage::NewConsumer(new_consumer)) => {
+                info!("Switched output device");
+                consumer = Some(new_consumer);
+            }
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        let now = Instant::now();
+        if now < next_tick {
+            continue;
+        }
+        next_tick += frame_duration;
+        // if the consumer blocked for a long time don't try to catch up frame by frame
+        if now > next_tick + frame_duration * 5 {
+            next_tick = now + frame_duration;
+        }
+
+        mixer.clear();
+        for ((addr, _), stream) in streams.iter_mut() {
+            if stream.fill(frame.samples().len(), &settings, &mut decoded_data, addr) {
+                stream.take(&mut frame);
+                mixer.add(&frame);
             }
-            _ => {}
+        }
+        if mixer.is_empty() {
+            // keeps the echo canceller's reference in step with the clock while nobody talks
+            let _ = echo_reference.send(AudioFrame::new(1, SAMPLE_RATE, FRAME_SIZE));
+            continue;
+        }
+        let mixed = mixer.mix();
+        let _ = echo_reference.send(mixed.to_mono());
+        let Some(output) = consumer.as_mut() else {
+            continue;
+        };
+        if let Err(e) = output.consume(mixed) {
+            error!("Error consuming data: {:?}", e);
+            consumer = None;
+            let _ = tx.send(ClientMessage::OutputFailed(e));
         }
     }
 }
 
-fn opus_encoder() -> Encoder {
-    Encoder::new(SAMPLE_RATE, Channels::Stereo, Voip).unwrap()
+/// Whether `audio` is in a format we can decode, the fields come straight off the wire
+fn playable(audio: &AudioData) -> bool {
+    matches!(audio.channels, 1 | 2) && codec::FRAME_DURATIONS_MS.contains(&audio.frame_ms)
 }
-fn opus_decoder() -> Decoder {
-    Decoder::new(SAMPLE_RATE, Channels::Stereo).unwrap()
+
+fn opus_decoder(channels: usize) -> Decoder {
+    let channels = if channels == 1 {
+        Channels::Mono
+    } else {
+        Channels::Stereo
+    };
+    Decoder::new(SAMPLE_RATE, channels).unwrap()
 }
 
-fn is_silence(pcm: &[i16], threshold: f32) -> bool {
-    if pcm.is_empty() {
-        return true;
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut sum = 0f64;
-    for &s in pcm {
-        sum += (s as f64) * (s as f64);
+    fn packet(seq_number: u32, channels: u8, frame_ms: u8) -> AudioData {
+        let mut encoder =
+            opus::Encoder::new(SAMPLE_RATE, Channels::Mono, opus::Application::Voip).unwrap();
+        let pcm = vec![0.0; codec::frame_size(20)];
+        AudioData {
+            stream_id: VOICE_STREAM,
+            timestamp: 0,
+            seq_number,
+            channels,
+            frame_ms,
+            data: encoder.encode_vec_float(&pcm, 4000).unwrap(),
+        }
     }
 
-    let rms = (sum / pcm.len() as f64).sqrt();
-    rms < threshold as f64
+    #[test]
+    fn drops_packets_it_cannot_decode() {
+        let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let mut stream = RemoteStream::new(&packet(1, 1, 20), 0.0);
+        stream.push(packet(1, 1, 20));
+        for (seq, channels, frame_ms) in [(2, 0, 20), (3, 3, 20), (4, 1, 0), (5, 1, 120)] {
+            assert!(!playable(&packet(seq, channels, frame_ms)));
+            stream.push(packet(seq, channels, frame_ms));
+        }
+        stream.push(packet(2, 1, 20));
+        assert_eq!(stream.channels, 1);
+        assert_eq!(stream.frame_ms, 20);
+        assert_eq!(stream.jitter.stats().received, 2);
+
+        let settings = PlaybackSettings::default();
+        let mut decoded = vec![0f32; codec::frame_size(MAX_FRAME_MS) * CHANNELS];
+        let samples = codec::frame_size(20) * CHANNELS;
+        assert!(stream.fill(2 * samples, &settings, &mut decoded, &addr));
+        assert_eq!(stream.pending.len(), 2 * samples);
+    }
 }
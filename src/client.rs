@@ -1,16 +1,31 @@
 use log::{debug, error, info, warn};
 use opus::Encoder;
 use std::mem;
-use std::sync::Arc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::net::{UdpSocket, lookup_host};
 
-use crate::server::{AudioData, Message, decode_message, encode_message};
-use crate::{BUF_SIZE, ErrorKind, MSG_SIZE, client};
+use crate::implementations::DeviceList;
+use crate::rtt::RttStats;
+use crate::server::{
+    AudioData, ERROR_NOT_CONNECTED, HeartbeatSettings, Hello, Message, OLDEST_VERSION,
+    PING_VERSION, PROTOCOL_VERSION, ReceiverReport, decode_message, encode_message,
+};
+use crate::{AudioProducer, BUF_SIZE, Consumer, ErrorKind, MSG_SIZE, client};
+
+// a lost server is tried again after this, twice as long after every attempt that couldn't
+// reach one
+const RECONNECT_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
 
 /// A network consumer that takes audio data and sends it over UDP
 pub struct NetworkClient {
-    pub socket: Arc<UdpSocket>,
+    // tried in order, host names are resolved again on every attempt
+    servers: Vec<String>,
+    // the connection to the current server, `None` while reconnecting
+    pub socket: Arc<RwLock<Option<Arc<UdpSocket>>>>,
     hangover: usize,
     hangover_limit: usize,
     muted: bool,
@@ -20,73 +35,141 @@ pub struct NetworkClient {
 }
 
 pub enum ClientMessage {
+    // trying to get in on this server
+    Connecting(String),
+    // the server accepted our hello, with the protocol version we speak from now on
+    Welcome(u8),
     Connect,
     Disconnect,
+    // the server turned us down, with its reason
+    Rejected(String),
+    // the server didn't like something we sent, with its error code
+    ServerError(u16, String),
+    // the server dropped us
+    Kicked(String),
+    // every server rejected us, we don't try anymore
+    NoServers,
+    // answer to a ping, nonce and timestamp
+    Pong(u32, u64),
+    // round trip time to the server, for the TUI and the jitter buffers
+    Rtt(RttStats),
+    // how far behind the sound card the capture and playback loops are, `None` where we
+    // don't know. The audio loops only fill in their own side.
+    DeviceLatency {
+        input: Option<Duration>,
+        output: Option<Duration>,
+    },
     ToggleMute,
     ToggleDeafen,
+    ToggleNoiseSuppression,
+    PttPress,
+    PttRelease,
+    // pause or resume our shared application audio
+    ToggleShare,
+    // stop or start playing what this sender shares
+    ToggleShareMute(std::net::SocketAddr),
+    // reading the shared audio failed, nothing is shared anymore
+    ShareFailed(ErrorKind),
     Audio(AudioData),
     RecvAudio(std::net::SocketAddr, AudioData),
+    Report(ReceiverReport),
+    RecvReport(std::net::SocketAddr, ReceiverReport),
+    // Device switching, a `None` device is the default one
+    ListDevices,
+    SwitchInput(Option<String>),
+    SwitchOutput(Option<String>),
+    NewProducer(Box<dyn AudioProducer + Send>),
+    NewConsumer(Box<dyn Consumer + Send>),
+    // the capture or playback loop dropped its device after this error
+    InputFailed(ErrorKind),
+    OutputFailed(ErrorKind),
     // TUI messages
+    Devices(DeviceList),
+    DeviceStatus(String),
+    // a failed device is being retried until it works again
+    InputLost,
+    OutputLost,
+    InputRestored,
+    OutputRestored,
     ShowActive(std::net::SocketAddr),
+    ShowSharing(std::net::SocketAddr),
     TransmitAudio(bool),
+    InputGain(f32),
     NewClient(std::net::SocketAddr),
     DeleteClient(std::net::SocketAddr),
     Exit,
 }
 
 impl NetworkClient {
-    pub async fn new(addr: &str, tx: Sender<ClientMessage>) -> Result<Self, ErrorKind> {
-        info!("Connecting to {}", addr);
-        let result = lookup_host(addr)
-            .await
-            .map_err(|e| ErrorKind::InitializationError2(e.to_string()))?;
-        let addr = result
-            .into_iter()
-            .next()
-            .ok_or(ErrorKind::InitializationError)?;
-        debug!("Connecting to {}", addr);
-        let consumer = UdpSocket::bind("0.0.0.0:0")
-            .await
-            .map(|s| NetworkClient {
-                socket: Arc::new(s),
-                hangover: 0,
-                hangover_limit: 10, // number of consecutive silent frames to send before stopping
-                muted: false,
-                tx: tx,
-            })
-            .map_err(|e| ErrorKind::InitializationError2(e.to_string()))?;
-        debug!("Socket bound to {}", consumer.socket.local_addr().unwrap());
-        consumer
-            .socket
-            .connect(addr)
-            .await
-            .map_err(|e| ErrorKind::InitializationError2(e.to_string()))?;
-
-        Ok(consumer)
+    pub fn new(servers: Vec<String>, tx: Sender<ClientMessage>) -> Self {
+        NetworkClient {
+            servers,
+            socket: Arc::new(RwLock::new(None)),
+            hangover: 0,
+            hangover_limit: 10, // number of consecutive silent frames to send before stopping
+            muted: false,
+            tx: tx,
+        }
     }
 
     pub async fn start(
         mut self,
         rx_receive_audio: Receiver<Message>,
         rx_net_out: Receiver<Message>,
+        heartbeat: HeartbeatSettings,
     ) -> () {
         let socket1 = self.socket.clone();
         let socket2 = self.socket.clone();
         let tx1 = self.tx.clone();
         let tx2 = self.tx.clone();
+        // 0 until the server's welcome tells us which version to use
+        let version1 = Arc::new(AtomicU8::new(0));
+        let version2 = version1.clone();
 
-        tokio::spawn(async move { client::send_udp(socket1, tx1, rx_net_out).await });
-        tokio::spawn(async move { client::receive_udp(socket2, rx_receive_audio, tx2).await });
+        tokio::spawn(async move {
+            client::send_udp(socket1, tx1, rx_net_out, version1, heartbeat).await
+        });
+        tokio::spawn(async move {
+            client::connect_loop(
+                self.servers,
+                socket2,
+                rx_receive_audio,
+                tx2,
+                version2,
+                heartbeat,
+            )
+            .await
+        });
     }
 }
 
 pub async fn send_udp(
-    socket: Arc<UdpSocket>,
+    socket: Arc<RwLock<Option<Arc<UdpSocket>>>>,
     tx: Sender<client::ClientMessage>,
     rx: Receiver<Message>,
+    version: Arc<AtomicU8>,
+    heartbeat: HeartbeatSettings,
 ) {
-    for msg in rx.iter() {
-        match socket.try_send(&encode_message(&msg)) {
+    loop {
+        let received = rx.recv_timeout(heartbeat.interval);
+        // after the wait, we may have reconnected in the meantime
+        let agreed = version.load(Ordering::Relaxed);
+        let msg = match received {
+            Ok(msg) => msg,
+            // nothing else went out for a while, tell the server we're still here
+            Err(RecvTimeoutError::Timeout) if agreed >= PING_VERSION => Message::Heartbeat,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        // the server takes nothing before it welcomed us, and while reconnecting there is
+        // nobody to send to
+        if agreed == 0 {
+            continue;
+        }
+        let Some(socket) = socket.read().unwrap().clone() else {
+            continue;
+        };
+        match socket.try_send(&encode_message(&msg, agreed)) {
             Ok(bytes_sent) => {
                 debug!(
                     "Sent {} bytes, msg type {:?}",
@@ -99,28 +182,214 @@ pub async fn send_udp(
     }
 }
 
-pub async fn receive_udp(
-    socket: Arc<UdpSocket>,
+/// Stays connected to the first server in the list that lets us in. When it drops us we
+/// start over from the top, servers that don't answer are tried again later and later.
+pub async fn connect_loop(
+    mut servers: Vec<String>,
+    socket: Arc<RwLock<Option<Arc<UdpSocket>>>>,
     rx_receive_audio: Receiver<Message>,
     tx: Sender<client::ClientMessage>,
+    version: Arc<AtomicU8>,
+    heartbeat: HeartbeatSettings,
 ) {
+    let mut index = 0;
+    let mut delay = RECONNECT_MIN;
+    loop {
+        let server = &servers[index];
+        info!("Connecting to {}", server);
+        let _ = tx.send(ClientMessage::Connecting(server.clone()));
+        let ending = match connect(server).await {
+            Ok(connection) => {
+                let connection = Arc::new(connection);
+                version.store(0, Ordering::Relaxed);
+                *socket.write().unwrap() = Some(connection.clone());
+                // a few times, the server answers every one and one might get lost
+                let hello = encode_message(&Message::Hello(Hello::ours()), PROTOCOL_VERSION);
+                for _ in 0..3 {
+                    if let Err(e) = connection.try_send(&hello) {
+                        error!("{:?}", ErrorKind::WriteError(e.to_string()));
+                    }
+                }
+                let ending = receive_udp(&connection, &tx, &version, heartbeat).await;
+                *socket.write().unwrap() = None;
+                version.store(0, Ordering::Relaxed);
+                ending
+            }
+            Err(e) => {
+                warn!("Can't connect to {}: {:?}", server, e);
+                Ending::Unreachable
+            }
+        };
+        match ending {
+            Ending::Lost => {
+                let _ = tx.send(ClientMessage::Disconnect);
+                index = 0;
+                delay = RECONNECT_MIN;
+            }
+            // asking again gets the same answer, the next server is tried right away
+            Ending::Rejected => {
+                info!("Not trying {} again", server);
+                servers.remove(index);
+                if servers.is_empty() {
+                    error!("Every server rejected us");
+                    let _ = tx.send(ClientMessage::NoServers);
+                    return;
+                }
+                index %= servers.len();
+                continue;
+            }
+            Ending::Unreachable => index = (index + 1) % servers.len(),
+        }
+        info!("Reconnecting in {:?}", delay);
+        tokio::time::sleep(delay).await;
+        if ending == Ending::Unreachable {
+            delay = (delay * 2).min(RECONNECT_MAX);
+        }
+    }
+}
+
+/// How a connection to a server ended
+#[derive(Debug, PartialEq)]
+enum Ending {
+    /// The server let us in and we lost it later
+    Lost,
+    /// The server turned us down
+    Rejected,
+    /// We never heard back, or the network failed before the server let us in
+    Unreachable,
+}
+
+/// Resolves `server` and opens a socket to the first address it has
+async fn connect(server: &str) -> Result<UdpSocket, ErrorKind> {
+    let addr = lookup_host(server)
+        .await
+        .map_err(|e| ErrorKind::InitializationError2(e.to_string()))?
+        .next()
+        .ok_or(ErrorKind::InitializationError)?;
+    debug!("Connecting to {}", addr);
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|e| ErrorKind::InitializationError2(e.to_string()))?;
+    debug!("Socket bound to {}", socket.local_addr().unwrap());
+    socket
+        .connect(addr)
+        .await
+        .map_err(|e| ErrorKind::InitializationError2(e.to_string()))?;
+    Ok(socket)
+}
+
+/// Hands what the server sends to the coordinator until the connection ends
+async fn receive_udp(
+    socket: &UdpSocket,
+    tx: &Sender<client::ClientMessage>,
+    version: &AtomicU8,
+    heartbeat: HeartbeatSettings,
+) -> Ending {
     let mut data = [0u8; MSG_SIZE as usize];
+    let mut welcomed = false;
+    // a failure of the link only counts against the server before it let us in
+    let lost = |welcomed| {
+        if welcomed {
+            Ending::Lost
+        } else {
+            Ending::Unreachable
+        }
+    };
     loop {
-        let (len, addr) = socket.recv_from(&mut data).await.unwrap();
-        let msg = decode_message(&data[..len]);
+        // servers from before heartbeats can't be told apart from quiet ones once we're in
+        let received = if !welcomed || version.load(Ordering::Relaxed) >= PING_VERSION {
+            match tokio::time::timeout(heartbeat.timeout, socket.recv_from(&mut data)).await {
+                Ok(received) => received,
+                Err(_) => {
+                    warn!("Nothing heard from the server in {:?}", heartbeat.timeout);
+                    return lost(welcomed);
+                }
+            }
+        } else {
+            socket.recv_from(&mut data).await
+        };
+        let (len, addr) = match received {
+            Ok(received) => received,
+            // e.g. refused because nothing listens on the port anymore
+            Err(e) => {
+                warn!("Can't receive from the server: {}", e);
+                return lost(welcomed);
+            }
+        };
+        let msg = match decode_message(&data[..len]) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Dropping packet from {}: {}", addr, e);
+                continue;
+            }
+        };
         debug!("Received message of type {:?}", msg);
         match msg {
             Message::AudioFrom(addr, data) => {
                 let _ = tx.send(ClientMessage::RecvAudio(addr, data));
             }
+            Message::ReportFrom(addr, report) => {
+                let _ = tx.send(ClientMessage::RecvReport(addr, report));
+            }
             Message::NewClient(addr) => {
                 let _ = tx.send(ClientMessage::NewClient(addr));
             }
             Message::DeleteClient(addr) => {
                 let _ = tx.send(ClientMessage::DeleteClient(addr));
             }
-            Message::Hello(addr) => {
-                let _ = tx.send(ClientMessage::Connect);
+            // every hello gets one
+            Message::Welcome { .. } if welcomed => {}
+            // a broken or confused server, we couldn't understand anything it sends
+            Message::Welcome {
+                version: agreed, ..
+            } if !(OLDEST_VERSION..=PROTOCOL_VERSION).contains(&agreed) => {
+                error!(
+                    "Server picked protocol version {}, we speak {} to {}",
+                    agreed, OLDEST_VERSION, PROTOCOL_VERSION
+                );
+                let _ = tx.send(ClientMessage::Rejected(format!(
+                    "the server picked protocol version {}, which we don't speak",
+                    agreed
+                )));
+                return Ending::Rejected;
+            }
+            Message::Welcome {
+                version: agreed,
+                capabilities,
+            } => {
+                debug!(
+                    "Server speaks protocol version {}, capabilities {:#x}",
+                    agreed, capabilities
+                );
+                welcomed = true;
+                version.store(agreed, Ordering::Relaxed);
+                let _ = tx.send(ClientMessage::Welcome(agreed));
+            }
+            Message::Pong { nonce, timestamp } => {
+                let _ = tx.send(ClientMessage::Pong(nonce, timestamp));
+            }
+            // an answer to one of our hellos, the server let us in with another
+            Message::Rejected(_) if welcomed => {}
+            Message::Rejected(reason) => {
+                error!("Server rejected us: {}", reason);
+                let _ = tx.send(ClientMessage::Rejected(reason));
+                return Ending::Rejected;
+            }
+            // the server restarted and forgot about us
+            Message::Error { code, .. } if code == ERROR_NOT_CONNECTED && welcomed => {
+                info!("The server doesn't know us anymore");
+                return Ending::Lost;
+            }
+            // before the welcome it's just something that went out too early
+            Message::Error { code, .. } if code == ERROR_NOT_CONNECTED => {}
+            Message::Error { code, reason } => {
+                error!("Server error {}: {}", code, reason);
+                let _ = tx.send(ClientMessage::ServerError(code, reason));
+            }
+            Message::Kicked { reason } => {
+                error!("Kicked by the server: {}", reason);
+                let _ = tx.send(ClientMessage::Kicked(reason));
+                return lost(welcomed);
             }
             _ => {}
         }
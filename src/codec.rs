@@ -0,0 +1,230 @@
+use std::ffi::CStr;
+
+use audiopus_sys as ffi;
+
+use crate::{AudioFrame, ErrorKind, SAMPLE_RATE};
+
+pub const FRAME_DURATIONS_MS: [u8; 4] = [10, 20, 40, 60];
+// keeps the largest packet well below the network buffer size
+const MAX_BITRATE: i32 = 256_000;
+const MIN_BITRATE: i32 = 6_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodecApplication {
+    Voip,
+    Audio,
+    LowDelay,
+}
+
+impl CodecApplication {
+    fn to_ffi(self) -> i32 {
+        match self {
+            CodecApplication::Voip => ffi::OPUS_APPLICATION_VOIP,
+            CodecApplication::Audio => ffi::OPUS_APPLICATION_AUDIO,
+            CodecApplication::LowDelay => ffi::OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+        }
+    }
+}
+
+/// Encoder settings of the outgoing stream
+#[derive(Debug, Clone)]
+pub struct CodecProfile {
+    pub bitrate: Option<i32>, // None lets opus pick one based on channels and frame size
+    pub complexity: u8,
+    pub vbr: bool,
+    pub channels: usize,
+    pub application: CodecApplication,
+    pub frame_ms: u8,
+}
+
+impl Default for CodecProfile {
+    fn default() -> Self {
+        CodecProfile {
+            bitrate: None,
+            complexity: 10,
+            vbr: true,
+            channels: 1,
+            application: CodecApplication::Voip,
+            frame_ms: 20,
+        }
+    }
+}
+
+impl CodecProfile {
+    /// Settings for shared application audio, full band stereo at a bitrate that keeps
+    /// music intact instead of the speech tuned defaults
+    pub fn music() -> Self {
+        CodecProfile {
+            bitrate: Some(128_000),
+            channels: 2,
+            application: CodecApplication::Audio,
+            ..Default::default()
+        }
+    }
+
+    /// Samples per channel in one frame
+    pub fn frame_size(&self) -> usize {
+        frame_size(self.frame_ms)
+    }
+
+    pub fn set_bitrate(&mut self, value: &str) -> Result<(), ErrorKind> {
+        if value == "auto" {
+            self.bitrate = None;
+            return Ok(());
+        }
+        match value.parse::<i32>() {
+            Ok(bitrate) if (MIN_BITRATE..=MAX_BITRATE).contains(&bitrate) => {
+                self.bitrate = Some(bitrate);
+                Ok(())
+            }
+            _ => Err(ErrorKind::ConfigError(format!(
+                "bitrate must be 'auto' or between {} and {}, got {}",
+                MIN_BITRATE, MAX_BITRATE, value
+            ))),
+        }
+    }
+
+    pub fn set_complexity(&mut self, value: &str) -> Result<(), ErrorKind> {
+        match value.parse::<u8>() {
+            Ok(complexity) if complexity <= 10 => {
+                self.complexity = complexity;
+                Ok(())
+            }
+            _ => Err(ErrorKind::ConfigError(format!(
+                "complexity must be between 0 and 10, got {}",
+                value
+            ))),
+        }
+    }
+
+    pub fn set_channels(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.channels = match value {
+            "1" | "mono" => 1,
+            "2" | "stereo" => 2,
+            _ => {
+                return Err(ErrorKind::ConfigError(format!(
+                    "channels must be mono or stereo, got {}",
+                    value
+                )));
+            }
+        };
+        Ok(())
+    }
+
+    pub fn set_application(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.application = match value {
+            "voip" => CodecApplication::Voip,
+            "audio" => CodecApplication::Audio,
+            "lowdelay" => CodecApplication::LowDelay,
+            _ => {
+                return Err(ErrorKind::ConfigError(format!(
+                    "application must be voip, audio or lowdelay, got {}",
+                    value
+                )));
+            }
+        };
+        Ok(())
+    }
+
+    pub fn set_frame_ms(&mut self, value: &str) -> Result<(), ErrorKind> {
+        match value.parse::<u8>() {
+            Ok(ms) if FRAME_DURATIONS_MS.contains(&ms) => {
+                self.frame_ms = ms;
+                Ok(())
+            }
+            _ => Err(ErrorKind::ConfigError(format!(
+                "frame duration must be one of {:?} ms, got {}",
+                FRAME_DURATIONS_MS, value
+            ))),
+        }
+    }
+}
+
+/// Samples per channel in a frame of the given duration
+pub fn frame_size(frame_ms: u8) -> usize {
+    SAMPLE_RATE as usize * frame_ms as usize / 1000
+}
+
+/// Opus encoder configured from a [`CodecProfile`]. The opus crate does not expose every
+/// encoder control we need, so this talks to libopus directly.
+pub struct Encoder {
+    ptr: *mut ffi::OpusEncoder,
+    channels: usize,
+}
+
+impl Encoder {
+    pub fn new(profile: &CodecProfile) -> Result<Self, ErrorKind> {
+        let mut error = 0;
+        let ptr = unsafe {
+            ffi::opus_encoder_create(
+                SAMPLE_RATE as i32,
+                profile.channels as i32,
+                profile.application.to_ffi(),
+                &mut error,
+            )
+        };
+        if error != ffi::OPUS_OK || ptr.is_null() {
+            return Err(ErrorKind::InitializationError2(opus_error(error)));
+        }
+        let mut encoder = Encoder {
+            ptr,
+            channels: profile.channels,
+        };
+        encoder.ctl(
+            ffi::OPUS_SET_BITRATE_REQUEST,
+            profile.bitrate.unwrap_or(ffi::OPUS_AUTO),
+        )?;
+        encoder.ctl(ffi::OPUS_SET_COMPLEXITY_REQUEST, profile.complexity as i32)?;
+        encoder.ctl(ffi::OPUS_SET_VBR_REQUEST, profile.vbr as i32)?;
+        encoder.ctl(ffi::OPUS_SET_INBAND_FEC_REQUEST, 1)?;
+        Ok(encoder)
+    }
+
+    /// Encodes one frame, returns the packet length
+    pub fn encode(&mut self, frame: &AudioFrame, output: &mut [u8]) -> Result<usize, ErrorKind> {
+        if frame.channels() != self.channels {
+            return Err(ErrorKind::EncodeError(format!(
+                "encoder takes {} channels, got {}",
+                self.channels,
+                frame.channels()
+            )));
+        }
+        let len = unsafe {
+            ffi::opus_encode_float(
+                self.ptr,
+                frame.samples().as_ptr(),
+                frame.frames() as i32,
+                output.as_mut_ptr(),
+                output.len() as i32,
+            )
+        };
+        if len < 0 {
+            return Err(ErrorKind::EncodeError(opus_error(len)));
+        }
+        Ok(len as usize)
+    }
+
+    pub fn set_packet_loss_perc(&mut self, percent: i32) -> Result<(), ErrorKind> {
+        self.ctl(ffi::OPUS_SET_PACKET_LOSS_PERC_REQUEST, percent)
+    }
+
+    fn ctl(&mut self, request: i32, value: i32) -> Result<(), ErrorKind> {
+        let result = unsafe { ffi::opus_encoder_ctl(self.ptr, request, value) };
+        if result != ffi::OPUS_OK {
+            return Err(ErrorKind::EncodeError(opus_error(result)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_encoder_destroy(self.ptr) }
+    }
+}
+
+fn opus_error(code: i32) -> String {
+    unsafe { CStr::from_ptr(ffi::opus_strerror(code)) }
+        .to_string_lossy()
+        .into_owned()
+}
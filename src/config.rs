@@ -0,0 +1,192 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::ErrorKind;
+use crate::agc::GainSettings;
+use crate::audio::{PlaybackSettings, TransmitMode};
+use crate::codec::CodecProfile;
+use crate::denoise::NoiseSuppressionSettings;
+use crate::implementations::BackendSettings;
+use crate::server::HeartbeatSettings;
+use crate::vad::VadSettings;
+
+const DEFAULT_SERVER: &str = "kopatz.dev:1234";
+
+/// Client settings. Read from the config file first, command line flags override them.
+///
+/// The file has one `key = value` pair per line, `#` starts a comment.
+#[derive(Debug)]
+pub struct Config {
+    /// Servers to connect to in order of preference, `servers()` has the default one if
+    /// none were given
+    pub servers: Vec<String>,
+    pub backend: BackendSettings,
+    pub playback: PlaybackSettings,
+    pub codec: CodecProfile,
+    /// Encoder settings of the shared application audio
+    pub share_codec: CodecProfile,
+    pub vad: VadSettings,
+    pub noise_suppression: NoiseSuppressionSettings,
+    pub echo_cancellation: bool,
+    pub gain: GainSettings,
+    pub transmit_mode: TransmitMode,
+    pub ptt_tail: Duration,
+    /// Used by the client and the server alike
+    pub heartbeat: HeartbeatSettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            servers: Vec::new(),
+            backend: BackendSettings::default(),
+            playback: PlaybackSettings::default(),
+            codec: CodecProfile::default(),
+            share_codec: CodecProfile::music(),
+            vad: VadSettings::default(),
+            noise_suppression: NoiseSuppressionSettings::default(),
+            echo_cancellation: true,
+            gain: GainSettings::default(),
+            transmit_mode: TransmitMode::VoiceActivity,
+            ptt_tail: Duration::from_millis(200),
+            heartbeat: HeartbeatSettings::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ErrorKind> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| ErrorKind::ConfigError(format!("Can't read {}: {}", path.display(), e)))?;
+        let mut config = Config::default();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(ErrorKind::ConfigError(format!(
+                    "{}:{}: expected 'key = value'",
+                    path.display(),
+                    number + 1
+                )));
+            };
+            config.set(key.trim(), value.trim()).map_err(|e| match e {
+                ErrorKind::ConfigError(msg) => {
+                    ErrorKind::ConfigError(format!("{}:{}: {}", path.display(), number + 1, msg))
+                }
+                e => e,
+            })?;
+        }
+        Ok(config)
+    }
+
+    pub fn servers(&self) -> Vec<String> {
+        if self.servers.is_empty() {
+            return vec![DEFAULT_SERVER.to_string()];
+        }
+        self.servers.clone()
+    }
+
+    /// Every `ip` adds fallbacks, one or a comma separated list
+    fn add_servers(&mut self, value: &str) -> Result<(), ErrorKind> {
+        let before = self.servers.len();
+        self.servers.extend(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|server| !server.is_empty())
+                .map(str::to_string),
+        );
+        if self.servers.len() == before {
+            return Err(ErrorKind::ConfigError(
+                "ip needs an address and port, e.g. kopatz.dev:1234".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ErrorKind> {
+        match key {
+            "ip" => self.add_servers(value)?,
+            "backend" => self.backend.set_backend(value)?,
+            "tone_frequency" => self.backend.set_tone_frequency(value)?,
+            "input_device" => self.backend.input_device = Some(value.to_string()),
+            "output_device" => self.backend.output_device = Some(value.to_string()),
+            "share" => self.backend.set_share_source(value)?,
+            "share_bitrate" => self.share_codec.set_bitrate(value)?,
+            "wav_input" => self.backend.wav_input = Some(PathBuf::from(value)),
+            "wav_output" => self.backend.wav_output = Some(PathBuf::from(value)),
+            "alsa_device" => self.backend.alsa_device = value.to_string(),
+            "alsa_period" => self.backend.set_alsa_period(value)?,
+            "alsa_buffer" => self.backend.set_alsa_buffer(value)?,
+            "pipewire_node_name" => self.backend.pipewire_node_name = value.to_string(),
+            "pipewire_quantum" => self.backend.set_pipewire_quantum(value)?,
+            "jack_client_name" => self.backend.jack_client_name = value.to_string(),
+            "jack_autoconnect" => self.backend.jack_autoconnect = parse_bool(value)?,
+            "plc" => self.playback.plc = parse_bool(value)?,
+            "bitrate" => self.codec.set_bitrate(value)?,
+            "complexity" => self.codec.set_complexity(value)?,
+            "vbr" => self.codec.vbr = parse_bool(value)?,
+            "channels" => self.codec.set_channels(value)?,
+            "application" => self.codec.set_application(value)?,
+            "frame_ms" => self.codec.set_frame_ms(value)?,
+            "vad_threshold" => self.vad.set_threshold(value)?,
+            "vad_attack_ms" => self.vad.set_attack(value)?,
+            "vad_hangover_ms" => self.vad.set_hangover(value)?,
+            "vad_calibrate" => self.vad.calibrate = parse_bool(value)?,
+            "noise_suppression" => self.noise_suppression.enabled = parse_bool(value)?,
+            "noise_suppression_strength" => self.noise_suppression.set_strength(value)?,
+            "echo_cancellation" => self.echo_cancellation = parse_bool(value)?,
+            "agc" => self.gain.automatic = parse_bool(value)?,
+            "agc_target" => self.gain.set_target(value)?,
+            "agc_max_gain" => self.gain.set_max_gain(value)?,
+            "input_gain" => self.gain.set_manual_gain(value)?,
+            "heartbeat_interval_ms" => self.heartbeat.set_interval(value)?,
+            "heartbeat_timeout_ms" => self.heartbeat.set_timeout(value)?,
+            "transmit_mode" => {
+                self.transmit_mode = match value {
+                    "vad" => TransmitMode::VoiceActivity,
+                    "ptt" => TransmitMode::PushToTalk,
+                    _ => {
+                        return Err(ErrorKind::ConfigError(format!(
+                            "transmit mode must be vad or ptt, got {}",
+                            value
+                        )));
+                    }
+                }
+            }
+            "ptt_tail_ms" => match value.parse::<u64>() {
+                Ok(ms) if ms <= 2000 => self.ptt_tail = Duration::from_millis(ms),
+                _ => {
+                    return Err(ErrorKind::ConfigError(format!(
+                        "ptt tail must be between 0 and 2000 ms, got {}",
+                        value
+                    )));
+                }
+            },
+            _ => return Err(ErrorKind::ConfigError(format!("Unknown key {}", key))),
+        }
+        Ok(())
+    }
+}
+
+/// `$XDG_CONFIG_HOME/kop-audio/config`, falling back to `~/.config/kop-audio/config`
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("kop-audio").join("config"))
+}
+
+fn parse_bool(value: &str) -> Result<bool, ErrorKind> {
+    match value {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(ErrorKind::ConfigError(format!(
+            "expected true or false, got {}",
+            value
+        ))),
+    }
+}
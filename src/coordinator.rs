@@ -1,51 +1,339 @@
-use std::{net::SocketAddr, sync::mpsc::{Receiver, Sender}};
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
 
-use crate::{client::ClientMessage, server::Message};
+use log::{debug, error, info};
 
+use crate::implementations::{
+    BackendSettings, close_share, list_devices, open_consumer, open_producer,
+};
+use crate::rtt::RttEstimator;
+use crate::server::{Message, PING_VERSION, SHARE_STREAM, VOICE_STREAM};
+use crate::{AudioProducer, Consumer, ErrorKind, client::ClientMessage};
+
+// a device that failed is tried again after this, twice as long after every attempt
+// that didn't work
+const RETRY_MIN: Duration = Duration::from_millis(500);
+const RETRY_MAX: Duration = Duration::from_secs(10);
+
+/// Opens capture and playback devices after startup, when the user picks another one or
+/// the one in use went away
+pub struct AudioDevices {
+    settings: BackendSettings,
+    // capture format, see `open_producer`
+    channels: usize,
+    fragsize: u32,
+    // set while a device is lost
+    input_retry: Option<Retry>,
+    output_retry: Option<Retry>,
+}
+
+struct Retry {
+    at: Instant,
+    delay: Duration,
+}
+
+impl Retry {
+    fn new() -> Self {
+        Retry {
+            at: Instant::now() + RETRY_MIN,
+            delay: RETRY_MIN,
+        }
+    }
+
+    fn failed(&mut self) {
+        self.delay = (self.delay * 2).min(RETRY_MAX);
+        self.at = Instant::now() + self.delay;
+    }
+}
+
+impl AudioDevices {
+    pub fn new(settings: BackendSettings, channels: usize, fragsize: u32) -> Self {
+        AudioDevices {
+            settings,
+            channels,
+            fragsize,
+            input_retry: None,
+            output_retry: None,
+        }
+    }
+
+    /// Opens `device` for recording and remembers it if that worked
+    fn open_input(
+        &mut self,
+        device: Option<String>,
+    ) -> Result<Box<dyn AudioProducer + Send>, ErrorKind> {
+        let mut settings = self.settings.clone();
+        settings.input_device = device;
+        let producer = open_producer(&settings, self.channels, self.fragsize)?;
+        self.settings = settings;
+        Ok(producer)
+    }
+
+    /// Opens `device` for playback and remembers it if that worked
+    fn open_output(
+        &mut self,
+        device: Option<String>,
+    ) -> Result<Box<dyn Consumer + Send>, ErrorKind> {
+        let mut settings = self.settings.clone();
+        settings.output_device = device;
+        let consumer = open_consumer(&settings)?;
+        self.settings = settings;
+        Ok(consumer)
+    }
+
+    /// Tries to get a lost input back, the chosen device first and then the default one
+    fn reopen_input(&mut self) -> Result<Box<dyn AudioProducer + Send>, ErrorKind> {
+        let device = self.settings.input_device.clone();
+        self.open_input(device.clone()).or_else(|e| match device {
+            Some(_) => self.open_input(None),
+            None => Err(e),
+        })
+    }
+
+    fn reopen_output(&mut self) -> Result<Box<dyn Consumer + Send>, ErrorKind> {
+        let device = self.settings.output_device.clone();
+        self.open_output(device.clone()).or_else(|e| match device {
+            Some(_) => self.open_output(None),
+            None => Err(e),
+        })
+    }
+
+    /// When the next lost device is due to be tried again
+    fn next_retry(&self) -> Option<Instant> {
+        [&self.input_retry, &self.output_retry]
+            .into_iter()
+            .flatten()
+            .map(|retry| retry.at)
+            .min()
+    }
+}
+
+// one channel per loop it talks to
+#[allow(clippy::too_many_arguments)]
 pub async fn run_coordinator(
     rx_msg: Receiver<ClientMessage>,
     tx_playback: Sender<ClientMessage>,
     tx_record: Sender<ClientMessage>,
+    // `None` when we don't share application audio
+    tx_share: Option<Sender<ClientMessage>>,
     tx_tui: Sender<ClientMessage>,
     tx_net_out: Sender<Message>,
     tx_net_in: Sender<Message>,
+    mut devices: AudioDevices,
 ) {
-    tx_net_out.send(Message::Hello("0.0.0.0:0".parse::<SocketAddr>().unwrap())).unwrap();
-    tx_net_out.send(Message::Hello("0.0.0.0:0".parse::<SocketAddr>().unwrap())).unwrap();
-    tx_net_out.send(Message::Hello("0.0.0.0:0".parse::<SocketAddr>().unwrap())).unwrap();
+    // pings the server once it welcomed us, servers too old to answer aren't pinged
+    let mut link: Option<RttEstimator> = None;
+    // what the audio loops last measured, the TUI shows both sides together
+    let mut input_latency: Option<Duration> = None;
+    let mut output_latency: Option<Duration> = None;
 
-    for cmd in rx_msg.iter() {
+    loop {
+        let deadline = [devices.next_retry(), link.as_ref().map(RttEstimator::next_ping)]
+            .into_iter()
+            .flatten()
+            .min();
+        let cmd = match deadline {
+            Some(at) => match rx_msg.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                Ok(cmd) => cmd,
+                Err(RecvTimeoutError::Timeout) => {
+                    retry_devices(&mut devices, &tx_record, &tx_playback, &tx_tui);
+                    if let Some(link) = link.as_mut() {
+                        ping_server(link, &tx_net_out);
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx_msg.recv() {
+                Ok(cmd) => cmd,
+                Err(_) => break,
+            },
+        };
         match cmd {
-            ClientMessage::Connect => {
+            ClientMessage::Connecting(server) => {
+                tx_tui.send(ClientMessage::Connecting(server)).unwrap();
+            }
+            ClientMessage::Welcome(version) => {
+                if version >= PING_VERSION {
+                    link = Some(RttEstimator::new());
+                }
                 tx_tui.send(ClientMessage::Connect).unwrap();
             }
+            // the server dropped us or went quiet, the network side is already reconnecting.
+            // Mute, deafen and a paused share live in the audio loops and carry over, the
+            // others are announced again by the next server.
+            ClientMessage::Disconnect => {
+                if let Some(link) = link.take() {
+                    info!("Lost the server, pings so far {:?}", link.stats());
+                }
+                tx_playback.send(ClientMessage::Disconnect).unwrap();
+                tx_tui.send(ClientMessage::Disconnect).unwrap();
+            }
+            ClientMessage::Pong(nonce, timestamp) => {
+                if let Some(link) = link.as_mut()
+                    && link.pong(nonce, timestamp, Instant::now()).is_some()
+                {
+                    tx_tui.send(ClientMessage::Rtt(link.stats())).unwrap();
+                    tx_playback.send(ClientMessage::Rtt(link.stats())).unwrap();
+                }
+            }
+            ClientMessage::DeviceLatency { input, output } => {
+                input_latency = input.or(input_latency);
+                output_latency = output.or(output_latency);
+                tx_tui
+                    .send(ClientMessage::DeviceLatency {
+                        input: input_latency,
+                        output: output_latency,
+                    })
+                    .unwrap();
+            }
+            ClientMessage::Rejected(reason) => {
+                tx_tui.send(ClientMessage::Rejected(reason)).unwrap();
+            }
+            ClientMessage::NoServers => {
+                tx_tui.send(ClientMessage::NoServers).unwrap();
+            }
+            ClientMessage::ServerError(code, reason) => {
+                tx_tui.send(ClientMessage::ServerError(code, reason)).unwrap();
+            }
+            ClientMessage::Kicked(reason) => {
+                tx_tui.send(ClientMessage::Kicked(reason)).unwrap();
+            }
             ClientMessage::Audio(audio) => {
-                tx_tui.send(ClientMessage::TransmitAudio(true)).unwrap();
+                if audio.stream_id == VOICE_STREAM {
+                    tx_tui.send(ClientMessage::TransmitAudio(true)).unwrap();
+                }
                 tx_net_out.send(Message::Audio(audio)).unwrap();
             }
             ClientMessage::RecvAudio(addr, audio) => {
+                let shown = if audio.stream_id == SHARE_STREAM {
+                    ClientMessage::ShowSharing(addr)
+                } else {
+                    ClientMessage::ShowActive(addr)
+                };
                 tx_playback.send(ClientMessage::RecvAudio(addr, audio)).unwrap();
-                tx_tui.send(ClientMessage::ShowActive(addr)).unwrap();
+                tx_tui.send(shown).unwrap();
+            }
+            ClientMessage::Report(report) => {
+                tx_net_out.send(Message::Report(report)).unwrap();
             }
+            ClientMessage::RecvReport(addr, report) => match (report.stream_id, &tx_share) {
+                (SHARE_STREAM, Some(tx_share)) => {
+                    let _ = tx_share.send(ClientMessage::RecvReport(addr, report));
+                }
+                (SHARE_STREAM, None) => {}
+                _ => tx_record.send(ClientMessage::RecvReport(addr, report)).unwrap(),
+            },
             ClientMessage::ToggleMute => {
                 tx_record.send(ClientMessage::ToggleMute).unwrap();
             }
+            ClientMessage::ToggleNoiseSuppression => {
+                tx_record
+                    .send(ClientMessage::ToggleNoiseSuppression)
+                    .unwrap();
+            }
+            ClientMessage::PttPress => {
+                tx_record.send(ClientMessage::PttPress).unwrap();
+            }
+            ClientMessage::PttRelease => {
+                tx_record.send(ClientMessage::PttRelease).unwrap();
+            }
+            ClientMessage::ToggleShare => {
+                if let Some(tx_share) = &tx_share {
+                    let _ = tx_share.send(ClientMessage::ToggleShare);
+                }
+            }
+            ClientMessage::ToggleShareMute(addr) => {
+                tx_playback.send(ClientMessage::ToggleShareMute(addr)).unwrap();
+            }
+            ClientMessage::ShareFailed(e) => {
+                error!("Shared audio failed: {:?}", e);
+                tx_tui
+                    .send(ClientMessage::DeviceStatus(format!(
+                        "Sharing stopped: {}",
+                        describe(&e)
+                    )))
+                    .unwrap();
+                tx_tui.send(ClientMessage::ShareFailed(e)).unwrap();
+            }
             ClientMessage::ToggleDeafen => {
                 tx_playback.send(ClientMessage::ToggleDeafen).unwrap();
             }
             ClientMessage::TransmitAudio(status) => {
                 tx_tui.send(ClientMessage::TransmitAudio(status)).unwrap();
             }
+            ClientMessage::InputGain(gain_db) => {
+                tx_tui.send(ClientMessage::InputGain(gain_db)).unwrap();
+            }
+            ClientMessage::ListDevices => match list_devices(&devices.settings) {
+                Ok(list) => tx_tui.send(ClientMessage::Devices(list)).unwrap(),
+                Err(e) => tx_tui
+                    .send(ClientMessage::DeviceStatus(format!(
+                        "Can't list devices: {}",
+                        describe(&e)
+                    )))
+                    .unwrap(),
+            },
+            // the new device is opened before the old one is dropped, so a device that
+            // doesn't work leaves the old one running
+            ClientMessage::SwitchInput(device) => match devices.open_input(device) {
+                Ok(producer) => use_input(&mut devices, producer, &tx_record, &tx_tui),
+                Err(e) => tx_tui
+                    .send(ClientMessage::DeviceStatus(format!(
+                        "Can't open input: {}",
+                        describe(&e)
+                    )))
+                    .unwrap(),
+            },
+            ClientMessage::SwitchOutput(device) => match devices.open_output(device) {
+                Ok(consumer) => use_output(&mut devices, consumer, &tx_playback, &tx_tui),
+                Err(e) => tx_tui
+                    .send(ClientMessage::DeviceStatus(format!(
+                        "Can't open output: {}",
+                        describe(&e)
+                    )))
+                    .unwrap(),
+            },
+            ClientMessage::InputFailed(e) => {
+                error!("Input device failed: {:?}", e);
+                devices.input_retry = Some(Retry::new());
+                input_latency = None;
+                tx_tui
+                    .send(ClientMessage::DeviceLatency {
+                        input: None,
+                        output: output_latency,
+                    })
+                    .unwrap();
+                tx_tui.send(ClientMessage::InputLost).unwrap();
+                tx_tui.send(ClientMessage::DeviceStatus(describe(&e))).unwrap();
+            }
+            ClientMessage::OutputFailed(e) => {
+                error!("Output device failed: {:?}", e);
+                devices.output_retry = Some(Retry::new());
+                output_latency = None;
+                tx_tui
+                    .send(ClientMessage::DeviceLatency {
+                        input: input_latency,
+                        output: None,
+                    })
+                    .unwrap();
+                tx_tui.send(ClientMessage::OutputLost).unwrap();
+                tx_tui.send(ClientMessage::DeviceStatus(describe(&e))).unwrap();
+            }
             ClientMessage::NewClient(addr) => {
                 tx_tui.send(ClientMessage::NewClient(addr)).unwrap();
             }
             ClientMessage::DeleteClient(addr) => {
+                tx_playback.send(ClientMessage::DeleteClient(addr)).unwrap();
                 tx_tui.send(ClientMessage::DeleteClient(addr)).unwrap();
             }
             ClientMessage::Exit => {
                 tx_net_out.send(Message::Bye).unwrap();
                 tx_net_out.send(Message::Bye).unwrap();
                 tx_net_out.send(Message::Bye).unwrap();
+                close_share(&devices.settings);
                 let _ = tokio::spawn(async move {
                     tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
                     std::process::exit(0);
@@ -56,6 +344,101 @@ pub async fn run_coordinator(
     }
 }
 
+/// Sends the next ping once it's due
+fn ping_server(link: &mut RttEstimator, tx_net_out: &Sender<Message>) {
+    let now = Instant::now();
+    if link.next_ping() > now {
+        return;
+    }
+    let (nonce, timestamp) = link.ping(now);
+    tx_net_out.send(Message::Ping { nonce, timestamp }).unwrap();
+}
+
+/// Reopens the lost devices whose retry is due
+fn retry_devices(
+    devices: &mut AudioDevices,
+    tx_record: &Sender<ClientMessage>,
+    tx_playback: &Sender<ClientMessage>,
+    tx_tui: &Sender<ClientMessage>,
+) {
+    let now = Instant::now();
+    if devices.input_retry.as_ref().is_some_and(|retry| retry.at <= now) {
+        match devices.reopen_input() {
+            Ok(producer) => {
+                info!("Reopened input {}", device_name(&devices.settings.input_device));
+                use_input(devices, producer, tx_record, tx_tui);
+            }
+            Err(e) => {
+                if let Some(retry) = devices.input_retry.as_mut() {
+                    retry.failed();
+                    debug!("Can't reopen input, trying again in {:?}: {:?}", retry.delay, e);
+                }
+            }
+        }
+    }
+    if devices.output_retry.as_ref().is_some_and(|retry| retry.at <= now) {
+        match devices.reopen_output() {
+            Ok(consumer) => {
+                info!("Reopened output {}", device_name(&devices.settings.output_device));
+                use_output(devices, consumer, tx_playback, tx_tui);
+            }
+            Err(e) => {
+                if let Some(retry) = devices.output_retry.as_mut() {
+                    retry.failed();
+                    debug!("Can't reopen output, trying again in {:?}: {:?}", retry.delay, e);
+                }
+            }
+        }
+    }
+}
+
+/// Hands a newly opened producer to the capture loop, which drops the old one
+fn use_input(
+    devices: &mut AudioDevices,
+    producer: Box<dyn AudioProducer + Send>,
+    tx_record: &Sender<ClientMessage>,
+    tx_tui: &Sender<ClientMessage>,
+) {
+    devices.input_retry = None;
+    tx_record.send(ClientMessage::NewProducer(producer)).unwrap();
+    tx_tui.send(ClientMessage::InputRestored).unwrap();
+    tx_tui
+        .send(ClientMessage::DeviceStatus(format!(
+            "Input: {}",
+            device_name(&devices.settings.input_device)
+        )))
+        .unwrap();
+}
+
+/// Hands a newly opened consumer to the playback loop, which drops the old one
+fn use_output(
+    devices: &mut AudioDevices,
+    consumer: Box<dyn Consumer + Send>,
+    tx_playback: &Sender<ClientMessage>,
+    tx_tui: &Sender<ClientMessage>,
+) {
+    devices.output_retry = None;
+    tx_playback.send(ClientMessage::NewConsumer(consumer)).unwrap();
+    tx_tui.send(ClientMessage::OutputRestored).unwrap();
+    tx_tui
+        .send(ClientMessage::DeviceStatus(format!(
+            "Output: {}",
+            device_name(&devices.settings.output_device)
+        )))
+        .unwrap();
+}
+
+fn device_name(device: &Option<String>) -> &str {
+    device.as_deref().unwrap_or("default")
+}
+
+fn describe(e: &ErrorKind) -> String {
+    match e {
+        ErrorKind::ConfigError(msg) | ErrorKind::InitializationError2(msg) => msg.clone(),
+        e => format!("{:?}", e),
+    }
+}
+
 pub fn receive_client_message(rx: &Option<Receiver<ClientMessage>>) -> Option<ClientMessage> {
     if let Some(rx) = rx {
         match rx.try_recv() {
@@ -0,0 +1,191 @@
+use std::sync::Arc;
+
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+
+use crate::{AudioFrame, ErrorKind};
+
+// 10ms at 48kHz, every supported frame duration is a multiple of this
+const HOP: usize = 480;
+const FFT_SIZE: usize = HOP * 2;
+const BINS: usize = FFT_SIZE / 2 + 1;
+// per block factor the noise estimate may grow by, about 1 dB per second
+const NOISE_RISE: f32 = 1.0023;
+// blocks at startup used to get a first noise estimate before tracking minima
+const INIT_BLOCKS: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct NoiseSuppressionSettings {
+    pub enabled: bool,
+    /// 0 barely touches the signal, 1 removes as much noise as possible
+    pub strength: f32,
+}
+
+impl Default for NoiseSuppressionSettings {
+    fn default() -> Self {
+        NoiseSuppressionSettings {
+            enabled: true,
+            strength: 0.5,
+        }
+    }
+}
+
+impl NoiseSuppressionSettings {
+    pub fn set_strength(&mut self, value: &str) -> Result<(), ErrorKind> {
+        match value.parse::<f32>() {
+            Ok(strength) if (0.0..=1.0).contains(&strength) => {
+                self.strength = strength;
+                Ok(())
+            }
+            _ => Err(ErrorKind::ConfigError(format!(
+                "noise suppression strength must be between 0 and 1, got {}",
+                value
+            ))),
+        }
+    }
+}
+
+/// Spectral subtraction on overlapping 20ms windows, with a noise estimate that follows
+/// the minimum power of every frequency bin. Adds 10ms of latency.
+pub struct NoiseSuppressor {
+    channels: Vec<ChannelState>,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    window: Vec<f32>,
+    // how much of the estimated noise power gets subtracted
+    over_subtraction: f32,
+    // lowest gain a bin can get, keeps some background so speech doesn't sound gated
+    gain_floor: f32,
+    time: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    block: Vec<f32>,
+}
+
+struct ChannelState {
+    previous: Vec<f32>,
+    overlap: Vec<f32>,
+    noise: Vec<f32>,
+    gains: Vec<f32>,
+    blocks: usize,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        ChannelState {
+            previous: vec![0.0; HOP],
+            overlap: vec![0.0; HOP],
+            noise: vec![0.0; BINS],
+            gains: vec![1.0; BINS],
+            blocks: 0,
+        }
+    }
+}
+
+impl NoiseSuppressor {
+    pub fn new(channels: usize, strength: f32) -> Self {
+        let mut planner = RealFftPlanner::<f32>::new();
+        // periodic sqrt-hann, applied before and after the transform so the overlapping
+        // halves add up to the original signal
+        let window = (0..FFT_SIZE)
+            .map(|i| {
+                let hann =
+                    0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / FFT_SIZE as f32).cos();
+                hann.sqrt()
+            })
+            .collect();
+        NoiseSuppressor {
+            channels: (0..channels).map(|_| ChannelState::new()).collect(),
+            forward: planner.plan_fft_forward(FFT_SIZE),
+            inverse: planner.plan_fft_inverse(FFT_SIZE),
+            window,
+            over_subtraction: 1.0 + 2.0 * strength,
+            gain_floor: 10f32.powf(-(6.0 + 24.0 * strength) / 20.0),
+            time: vec![0.0; FFT_SIZE],
+            spectrum: vec![Complex::default(); BINS],
+            block: vec![0.0; HOP],
+        }
+    }
+
+    /// Forgets the noise estimate, e.g. after suppression was switched off for a while
+    pub fn reset(&mut self) {
+        for channel in self.channels.iter_mut() {
+            *channel = ChannelState::new();
+        }
+    }
+
+    /// Cleans an interleaved frame in place, its length must be a multiple of 10ms
+    pub fn process(&mut self, frame: &mut AudioFrame) {
+        let channels = self.channels.len();
+        for chunk in frame.samples_mut().chunks_mut(HOP * channels) {
+            for c in 0..channels {
+                for (i, s) in chunk.iter().skip(c).step_by(channels).enumerate() {
+                    self.block[i] = *s;
+                }
+                self.process_block(c);
+                for (i, s) in chunk.iter_mut().skip(c).step_by(channels).enumerate() {
+                    *s = self.block[i];
+                }
+            }
+        }
+    }
+
+    fn process_block(&mut self, c: usize) {
+        let state = &mut self.channels[c];
+        self.time[..HOP].copy_from_slice(&state.previous);
+        self.time[HOP..].copy_from_slice(&self.block);
+        state.previous.copy_from_slice(&self.block);
+        for (s, w) in self.time.iter_mut().zip(&self.window) {
+            *s *= w;
+        }
+        if self
+            .forward
+            .process(&mut self.time, &mut self.spectrum)
+            .is_err()
+        {
+            return;
+        }
+
+        state.blocks += 1;
+        for (k, bin) in self.spectrum.iter_mut().enumerate() {
+            let power = bin.norm_sqr();
+            let noise = &mut state.noise[k];
+            if state.blocks <= INIT_BLOCKS {
+                *noise += (power - *noise) / state.blocks as f32;
+            } else if power < *noise {
+                *noise = 0.9 * *noise + 0.1 * power;
+            } else {
+                *noise = (*noise * NOISE_RISE).min(power);
+            }
+
+            let gain = if power > 0.0 {
+                (1.0 - self.over_subtraction * *noise / power).max(self.gain_floor)
+            } else {
+                self.gain_floor
+            };
+            // open immediately but close slowly, isolated bins popping up sound like chirps
+            let smoothed = &mut state.gains[k];
+            *smoothed = if gain > *smoothed {
+                gain
+            } else {
+                0.5 * *smoothed + 0.5 * gain
+            };
+            *bin *= *smoothed;
+        }
+        // the DC and nyquist bins have to stay real for the inverse transform
+        self.spectrum[0].im = 0.0;
+        self.spectrum[BINS - 1].im = 0.0;
+        if self
+            .inverse
+            .process(&mut self.spectrum, &mut self.time)
+            .is_err()
+        {
+            return;
+        }
+
+        let scale = 1.0 / FFT_SIZE as f32;
+        for (i, out) in self.block.iter_mut().enumerate() {
+            *out = state.overlap[i] + self.time[i] * self.window[i] * scale;
+            state.overlap[i] = self.time[HOP + i] * self.window[HOP + i] * scale;
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+/// A block of interleaved samples between -1.0 and 1.0 together with their layout. Backends
+/// fill and drain these, the effects, the mixer and the encoder work on them directly, so
+/// raw 16 bit samples only exist at the edges where a device or file wants them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioFrame {
+    samples: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+}
+
+impl AudioFrame {
+    /// A silent frame holding `frames` samples per channel
+    pub fn new(channels: usize, sample_rate: u32, frames: usize) -> Self {
+        AudioFrame {
+            samples: vec![0.0; frames * channels],
+            channels,
+            sample_rate,
+        }
+    }
+
+    /// Wraps interleaved samples, a trailing partial frame is dropped
+    pub fn from_samples(channels: usize, sample_rate: u32, mut samples: Vec<f32>) -> Self {
+        samples.truncate(samples.len() / channels * channels);
+        AudioFrame {
+            samples,
+            channels,
+            sample_rate,
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Samples per channel
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_micros(self.frames() as u64 * 1_000_000 / self.sample_rate as u64)
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn samples_mut(&mut self) -> &mut [f32] {
+        &mut self.samples
+    }
+
+    pub fn silence(&mut self) {
+        self.samples.fill(0.0);
+    }
+
+    /// Overwrites the frame with 16 bit samples, slots `pcm` runs out for stay silent
+    pub fn fill_i16(&mut self, pcm: impl IntoIterator<Item = i16>) {
+        let mut pcm = pcm.into_iter();
+        for s in self.samples.iter_mut() {
+            *s = pcm.next().map_or(0.0, |s| s as f32 / i16::MAX as f32);
+        }
+    }
+
+    /// The samples as 16 bit integers, clipped to full scale
+    pub fn iter_i16(&self) -> impl Iterator<Item = i16> + '_ {
+        self.samples
+            .iter()
+            .map(|&s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+    }
+
+    /// Single channel copy holding the average of all channels
+    pub fn to_mono(&self) -> AudioFrame {
+        let samples = self
+            .samples
+            .chunks_exact(self.channels)
+            .map(|frame| frame.iter().sum::<f32>() / self.channels as f32)
+            .collect();
+        AudioFrame::from_samples(1, self.sample_rate, samples)
+    }
+}
@@ -0,0 +1,334 @@
+use std::ffi::{CStr, CString, c_char, c_int, c_long, c_uint, c_ulong, c_void};
+use std::ptr;
+
+use log::info;
+
+use super::BackendSettings;
+use crate::{AudioFrame, AudioProducer, CHANNELS, Consumer, ErrorKind, SAMPLE_RATE};
+
+// the handful of libasound calls we need, linked through pkg-config in build.rs
+#[allow(non_camel_case_types)]
+type snd_pcm_t = c_void;
+#[allow(non_camel_case_types)]
+type snd_pcm_hw_params_t = c_void;
+
+const SND_PCM_STREAM_PLAYBACK: c_int = 0;
+const SND_PCM_STREAM_CAPTURE: c_int = 1;
+const SND_PCM_ACCESS_RW_INTERLEAVED: c_int = 3;
+#[cfg(target_endian = "little")]
+const SND_PCM_FORMAT_S16: c_int = 2;
+#[cfg(target_endian = "big")]
+const SND_PCM_FORMAT_S16: c_int = 3;
+// a device that can't take or give a frame for this long is considered stuck
+const WAIT_TIMEOUT_MS: c_int = 1000;
+
+unsafe extern "C" {
+    fn snd_pcm_open(
+        pcm: *mut *mut snd_pcm_t,
+        name: *const c_char,
+        stream: c_int,
+        mode: c_int,
+    ) -> c_int;
+    fn snd_pcm_close(pcm: *mut snd_pcm_t) -> c_int;
+    fn snd_pcm_prepare(pcm: *mut snd_pcm_t) -> c_int;
+    fn snd_pcm_recover(pcm: *mut snd_pcm_t, err: c_int, silent: c_int) -> c_int;
+    fn snd_pcm_wait(pcm: *mut snd_pcm_t, timeout: c_int) -> c_int;
+    fn snd_pcm_readi(pcm: *mut snd_pcm_t, buffer: *mut c_void, frames: c_ulong) -> c_long;
+    fn snd_pcm_writei(pcm: *mut snd_pcm_t, buffer: *const c_void, frames: c_ulong) -> c_long;
+    fn snd_pcm_hw_params_malloc(params: *mut *mut snd_pcm_hw_params_t) -> c_int;
+    fn snd_pcm_hw_params_free(params: *mut snd_pcm_hw_params_t);
+    fn snd_pcm_hw_params_any(pcm: *mut snd_pcm_t, params: *mut snd_pcm_hw_params_t) -> c_int;
+    fn snd_pcm_hw_params_set_access(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        access: c_int,
+    ) -> c_int;
+    fn snd_pcm_hw_params_set_format(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        format: c_int,
+    ) -> c_int;
+    fn snd_pcm_hw_params_set_channels(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        channels: c_uint,
+    ) -> c_int;
+    fn snd_pcm_hw_params_set_rate_near(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        rate: *mut c_uint,
+        dir: *mut c_int,
+    ) -> c_int;
+    fn snd_pcm_hw_params_set_period_size_near(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        frames: *mut c_ulong,
+        dir: *mut c_int,
+    ) -> c_int;
+    fn snd_pcm_hw_params_set_buffer_size_near(
+        pcm: *mut snd_pcm_t,
+        params: *mut snd_pcm_hw_params_t,
+        frames: *mut c_ulong,
+    ) -> c_int;
+    fn snd_pcm_hw_params(pcm: *mut snd_pcm_t, params: *mut snd_pcm_hw_params_t) -> c_int;
+    fn snd_strerror(errnum: c_int) -> *const c_char;
+}
+
+/// An opened and configured PCM device
+struct Pcm {
+    handle: *mut snd_pcm_t,
+    channels: usize,
+    // what the device settled on, the caller resamples if it isn't ours
+    rate: u32,
+}
+
+// the handle is only ever used by the one audio thread that owns it
+unsafe impl Send for Pcm {}
+
+impl Pcm {
+    fn open(settings: &BackendSettings, stream: c_int, channels: usize) -> Result<Self, ErrorKind> {
+        let name = CString::new(settings.alsa_device.as_str()).map_err(|_| {
+            ErrorKind::ConfigError("alsa device name contains a NUL byte".to_string())
+        })?;
+        let mut handle = ptr::null_mut();
+        check(
+            unsafe { snd_pcm_open(&mut handle, name.as_ptr(), stream, 0) },
+            "open",
+        )?;
+        // from here on Drop closes the handle if configuring fails
+        let mut pcm = Pcm {
+            handle,
+            channels,
+            rate: SAMPLE_RATE,
+        };
+
+        let mut params = ptr::null_mut();
+        check(
+            unsafe { snd_pcm_hw_params_malloc(&mut params) },
+            "allocate parameters",
+        )?;
+        let result = pcm.configure(params, settings);
+        unsafe { snd_pcm_hw_params_free(params) };
+        let (rate, period, buffer) = result?;
+        pcm.rate = rate;
+        info!(
+            "Opened alsa {} device {} with {} channels at {} Hz, period {} frames, buffer {} frames",
+            if stream == SND_PCM_STREAM_CAPTURE {
+                "capture"
+            } else {
+                "playback"
+            },
+            settings.alsa_device,
+            channels,
+            rate,
+            period,
+            buffer
+        );
+        Ok(pcm)
+    }
+
+    fn configure(
+        &self,
+        params: *mut snd_pcm_hw_params_t,
+        settings: &BackendSettings,
+    ) -> Result<(u32, c_ulong, c_ulong), ErrorKind> {
+        let pcm = self.handle;
+        unsafe {
+            check(snd_pcm_hw_params_any(pcm, params), "read parameters")?;
+            check(
+                snd_pcm_hw_params_set_access(pcm, params, SND_PCM_ACCESS_RW_INTERLEAVED),
+                "set access",
+            )?;
+            check(
+                snd_pcm_hw_params_set_format(pcm, params, SND_PCM_FORMAT_S16),
+                "set format",
+            )?;
+            check(
+                snd_pcm_hw_params_set_channels(pcm, params, self.channels as c_uint),
+                "set channels",
+            )?;
+            // hw: devices may only offer 44.1 kHz or 16 kHz, take what's closest
+            let mut rate = SAMPLE_RATE as c_uint;
+            check(
+                snd_pcm_hw_params_set_rate_near(pcm, params, &mut rate, ptr::null_mut()),
+                "set rate",
+            )?;
+            let mut period = settings.alsa_period as c_ulong;
+            check(
+                snd_pcm_hw_params_set_period_size_near(pcm, params, &mut period, ptr::null_mut()),
+                "set period size",
+            )?;
+            let mut buffer = settings.alsa_buffer as c_ulong;
+            check(
+                snd_pcm_hw_params_set_buffer_size_near(pcm, params, &mut buffer),
+                "set buffer size",
+            )?;
+            check(snd_pcm_hw_params(pcm, params), "apply parameters")?;
+            check(snd_pcm_prepare(pcm), "prepare")?;
+            Ok((rate, period, buffer))
+        }
+    }
+
+    /// Runs `transfer` until all frames went through, recovering from over- and underruns
+    fn transfer(
+        &mut self,
+        frames: usize,
+        mut transfer: impl FnMut(*mut snd_pcm_t, usize) -> c_long,
+    ) -> Result<(), String> {
+        let mut done = 0;
+        while done < frames {
+            let result = match transfer(self.handle, done) {
+                // nothing moved, e.g. a paused stream, wait instead of spinning on it
+                0 => match unsafe { snd_pcm_wait(self.handle, WAIT_TIMEOUT_MS) } {
+                    0 => return Err("the device stopped moving audio".to_string()),
+                    ready if ready < 0 => ready as c_long,
+                    _ => continue,
+                },
+                result => result,
+            };
+            if result < 0 {
+                let recovered = unsafe { snd_pcm_recover(self.handle, result as c_int, 1) };
+                if recovered < 0 {
+                    return Err(strerror(recovered));
+                }
+                continue;
+            }
+            done += result as usize;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pcm {
+    fn drop(&mut self) {
+        unsafe { snd_pcm_close(self.handle) };
+    }
+}
+
+pub struct AlsaProducer {
+    pcm: Pcm,
+    samples: Vec<i16>,
+}
+
+impl AlsaProducer {
+    pub fn new(settings: &BackendSettings, channels: usize) -> Result<Self, ErrorKind> {
+        Ok(AlsaProducer {
+            pcm: Pcm::open(settings, SND_PCM_STREAM_CAPTURE, channels)?,
+            samples: Vec::new(),
+        })
+    }
+}
+
+impl AudioProducer for AlsaProducer {
+    fn produce(&mut self, frame: &mut AudioFrame) -> Result<(), ErrorKind> {
+        let channels = self.pcm.channels;
+        let frames = frame.frames();
+        self.samples.resize(frames * channels, 0);
+        let buffer = self.samples.as_mut_ptr();
+        self.pcm
+            .transfer(frames, |pcm, done| unsafe {
+                snd_pcm_readi(
+                    pcm,
+                    buffer.add(done * channels) as *mut c_void,
+                    (frames - done) as c_ulong,
+                )
+            })
+            .map_err(|_| ErrorKind::ReadError)?;
+        frame.fill_i16(self.samples.iter().copied());
+        Ok(())
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.pcm.rate
+    }
+}
+
+pub struct AlsaConsumer {
+    pcm: Pcm,
+    samples: Vec<i16>,
+}
+
+impl AlsaConsumer {
+    pub fn new(settings: &BackendSettings) -> Result<Self, ErrorKind> {
+        Ok(AlsaConsumer {
+            pcm: Pcm::open(settings, SND_PCM_STREAM_PLAYBACK, CHANNELS)?,
+            samples: Vec::new(),
+        })
+    }
+}
+
+impl Consumer for AlsaConsumer {
+    fn consume(&mut self, frame: &AudioFrame) -> Result<usize, ErrorKind> {
+        let channels = self.pcm.channels;
+        self.samples.clear();
+        self.samples.extend(frame.iter_i16());
+        let frames = self.samples.len() / channels;
+        let buffer = self.samples.as_ptr();
+        self.pcm
+            .transfer(frames, |pcm, done| unsafe {
+                snd_pcm_writei(
+                    pcm,
+                    buffer.add(done * channels) as *const c_void,
+                    (frames - done) as c_ulong,
+                )
+            })
+            .map_err(ErrorKind::WriteError)?;
+        Ok(frames)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.pcm.rate
+    }
+}
+
+fn check(result: c_int, what: &str) -> Result<(), ErrorKind> {
+    if result < 0 {
+        return Err(ErrorKind::InitializationError2(format!(
+            "alsa: can't {}: {}",
+            what,
+            strerror(result)
+        )));
+    }
+    Ok(())
+}
+
+fn strerror(code: c_int) -> String {
+    unsafe { CStr::from_ptr(snd_strerror(code)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FRAME_SIZE;
+
+    // the null plugin throws away what is played and records silence, it needs no sound card
+    fn null_device() -> BackendSettings {
+        BackendSettings {
+            alsa_device: "null".to_string(),
+            ..BackendSettings::default()
+        }
+    }
+
+    #[test]
+    fn null_device_round_trip() {
+        let settings = null_device();
+        let mut consumer = AlsaConsumer::new(&settings).unwrap();
+        let mut producer = AlsaProducer::new(&settings, CHANNELS).unwrap();
+        assert_eq!(consumer.sample_rate(), SAMPLE_RATE);
+        assert_eq!(producer.sample_rate(), SAMPLE_RATE);
+
+        let samples = (0..FRAME_SIZE * CHANNELS)
+            .map(|i| (i as f32 / 100.0).sin() * 0.5)
+            .collect();
+        let played = AudioFrame::from_samples(CHANNELS, SAMPLE_RATE, samples);
+        let mut recorded = AudioFrame::new(CHANNELS, SAMPLE_RATE, FRAME_SIZE);
+        for _ in 0..4 {
+            assert_eq!(consumer.consume(&played).unwrap(), FRAME_SIZE);
+            producer.produce(&mut recorded).unwrap();
+            assert_eq!(recorded.frames(), FRAME_SIZE);
+            assert!(recorded.samples().iter().all(|&s| s == 0.0));
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::cell::UnsafeCell;
+use std::ffi::{CStr, CString, c_char, c_int, c_ulong, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use super::ring::{RingReader, RingWriter, ring};
+use super::BackendSettings;
+use crate::{AudioFrame, AudioProducer, CHANNELS, Consumer, ErrorKind, SAMPLE_RATE};
+
+// the libjack calls we need, linked through pkg-config in build.rs
+#[repr(C)]
+#[derive(Default)]
+struct JackLatencyRange {
+    min: u32,
+    max: u32,
+}
+
+unsafe extern "C" {
+    fn jack_client_open(
+        name: *const c_char,
+        options: c_int,
+        status: *mut c_int,
+        ...
+    ) -> *mut c_void;
+    fn jack_client_close(client: *mut c_void) -> c_int;
+    fn jack_get_sample_rate(client: *mut c_void) -> u32;
+    fn jack_get_buffer_size(client: *mut c_void) -> u32;
+    fn jack_port_register(
+        client: *mut c_void,
+        name: *const c_char,
+        port_type: *const c_char,
+        flags: c_ulong,
+        buffer_size: c_ulong,
+    ) -> *mut c_void;
+    fn jack_port_name(port: *mut c_void) -> *const c_char;
+    fn jack_port_get_buffer(port: *mut c_void, frames: u32) -> *mut f32;
+    fn jack_port_get_latency_range(port: *mut c_void, mode: c_int, range: *mut JackLatencyRange);
+    fn jack_set_process_callback(
+        client: *mut c_void,
+        callback: unsafe extern "C" fn(u32, *mut c_void) -> c_int,
+        arg: *mut c_void,
+    ) -> c_int;
+    fn jack_on_shutdown(
+        client: *mut c_void,
+        callback: unsafe extern "C" fn(*mut c_void),
+        arg: *mut c_void,
+    );
+    fn jack_activate(client: *mut c_void) -> c_int;
+    fn jack_deactivate(client: *mut c_void) -> c_int;
+    fn jack_get_ports(
+        client: *mut c_void,
+        name_pattern: *const c_char,
+        type_pattern: *const c_char,
+        flags: c_ulong,
+    ) -> *mut *const c_char;
+    fn jack_connect(
+        client: *mut c_void,
+        source: *const c_char,
+        destination: *const c_char,
+    ) -> c_int;
+    fn jack_free(ptr: *mut c_void);
+}
+
+const JACK_NO_START_SERVER: c_int = 0x01;
+const JACK_PORT_IS_INPUT: c_ulong = 0x1;
+const JACK_PORT_IS_OUTPUT: c_ulong = 0x2;
+const JACK_PORT_IS_PHYSICAL: c_ulong = 0x4;
+const JACK_CAPTURE_LATENCY: c_int = 0;
+const JACK_PLAYBACK_LATENCY: c_int = 1;
+const JACK_DEFAULT_AUDIO_TYPE: &CStr = c"32 bit float mono audio";
+
+// the callback converts in pieces of this many frames so it never allocates, whatever
+// buffer size the server runs with
+const CHUNK_FRAMES: usize = 256;
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+// largest encoder frame, the capture queue holds a few of them
+const MAX_FRAME_MS: usize = 60;
+
+enum Side {
+    Capture(RingWriter),
+    Playback(RingReader),
+}
+
+/// State the process callback works on, lives in a box so its address stays put
+struct Shared {
+    ports: Vec<*mut c_void>,
+    // only ever touched by the process callback
+    side: UnsafeCell<Side>,
+    failed: AtomicBool,
+}
+
+/// A client with one port per channel
+struct Client {
+    client: *mut c_void,
+    shared: Box<Shared>,
+    // the server's rate, the caller resamples if it isn't ours
+    rate: u32,
+}
+
+// the client is thread safe, the port buffers are only used inside the process callback
+unsafe impl Send for Client {}
+
+impl Client {
+    fn open(settings: &BackendSettings, channels: usize, side: Side) -> Result<Self, ErrorKind> {
+        let capture = matches!(side, Side::Capture(_));
+        let (suffix, port_prefix, flags) = if capture {
+            ("input", "in", JACK_PORT_IS_INPUT)
+        } else {
+            ("output", "out", JACK_PORT_IS_OUTPUT)
+        };
+        let name = cstring(&format!("{}-{}", settings.jack_client_name, suffix))?;
+        let mut status = 0;
+        let client = unsafe { jack_client_open(name.as_ptr(), JACK_NO_START_SERVER, &mut status) };
+        if client.is_null() {
+            return Err(ErrorKind::InitializationError2(format!(
+                "jack: can't open client, status {:#x}. Is the server running?",
+                status
+            )));
+        }
+        let mut jack = Client {
+            client,
+            shared: Box::new(Shared {
+                ports: Vec::new(),
+                side: UnsafeCell::new(side),
+                failed: AtomicBool::new(false),
+            }),
+            rate: unsafe { jack_get_sample_rate(client) },
+        };
+
+        for channel in 1..=channels {
+            let port_name = cstring(&format!("{}_{}", port_prefix, channel))?;
+            let port = unsafe {
+                jack_port_register(
+                    client,
+                    port_name.as_ptr(),
+                    JACK_DEFAULT_AUDIO_TYPE.as_ptr(),
+                    flags,
+                    0,
+                )
+            };
+            if port.is_null() {
+                return Err(ErrorKind::InitializationError2(format!(
+                    "jack: can't register port {}",
+                    port_name.to_string_lossy()
+                )));
+            }
+            jack.shared.ports.push(port);
+        }
+
+        let shared = &*jack.shared as *const Shared as *mut c_void;
+        unsafe {
+            jack_set_process_callback(client, on_process, shared);
+            jack_on_shutdown(client, on_shutdown, shared);
+            if jack_activate(client) != 0 {
+                return Err(ErrorKind::InitializationError2(
+                    "jack: can't activate client".to_string(),
+                ));
+            }
+        }
+        info!(
+            "Opened jack client {} with {} ports at {} Hz, buffer size {} frames",
+            name.to_string_lossy(),
+            channels,
+            jack.rate,
+            unsafe { jack_get_buffer_size(client) }
+        );
+        if settings.jack_autoconnect {
+            jack.connect_physical(capture);
+        }
+        Ok(jack)
+    }
+
+    /// Wires our ports to the system's physical ports in order, like most jack apps do
+    fn connect_physical(&self, capture: bool) {
+        // a physical capture port is an output from the graph's point of view
+        let flags = JACK_PORT_IS_PHYSICAL
+            | if capture {
+                JACK_PORT_IS_OUTPUT
+            } else {
+                JACK_PORT_IS_INPUT
+            };
+        unsafe {
+            let physical = jack_get_ports(
+                self.client,
+                ptr::null(),
+                JACK_DEFAULT_AUDIO_TYPE.as_ptr(),
+                flags,
+            );
+            if physical.is_null() {
+                warn!("jack: no physical ports to connect to");
+                return;
+            }
+            for (i, &port) in self.shared.ports.iter().enumerate() {
+                let other = *physical.add(i);
+                if other.is_null() {
+                    break;
+                }
+                let ours = jack_port_name(port);
+                let (source, destination) = if capture {
+                    (other, ours)
+                } else {
+                    (ours, other)
+                };
+                if jack_connect(self.client, source, destination) != 0 {
+                    warn!(
+                        "jack: can't connect {} to {}",
+                        CStr::from_ptr(source).to_string_lossy(),
+                        CStr::from_ptr(destination).to_string_lossy()
+                    );
+                }
+            }
+            jack_free(physical as *mut c_void);
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.shared.failed.load(Ordering::Relaxed) {
+            return Err("jack server shut down".to_string());
+        }
+        Ok(())
+    }
+
+    /// Port latency reported by the graph plus what sits in our queue
+    fn latency(&self, queued: usize, mode: c_int) -> Option<Duration> {
+        let port = *self.shared.ports.first()?;
+        let mut range = JackLatencyRange::default();
+        unsafe { jack_port_get_latency_range(port, mode, &mut range) };
+        let frames = range.max as u64 + (queued / self.shared.ports.len()) as u64;
+        Some(Duration::from_micros(frames * 1_000_000 / self.rate as u64))
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        unsafe {
+            jack_deactivate(self.client);
+            jack_client_close(self.client);
+        }
+    }
+}
+
+unsafe extern "C" fn on_shutdown(arg: *mut c_void) {
+    let shared = unsafe { &*(arg as *const Shared) };
+    shared.failed.store(true, Ordering::Relaxed);
+}
+
+/// Runs on the jack realtime thread once per period, must not block or allocate
+unsafe extern "C" fn on_process(frames: u32, arg: *mut c_void) -> c_int {
+    let shared = unsafe { &*(arg as *const Shared) };
+    let channels = shared.ports.len();
+    let frames = frames as usize;
+    let buffers: [*mut f32; CHANNELS] = std::array::from_fn(|c| {
+        shared.ports.get(c).map_or(ptr::null_mut(), |&port| unsafe {
+            jack_port_get_buffer(port, frames as u32)
+        })
+    });
+    let mut chunk = [0i16; CHUNK_FRAMES * CHANNELS];
+    let mut start = 0;
+    while start < frames {
+        let len = (frames - start).min(CHUNK_FRAMES);
+        let samples = &mut chunk[..len * channels];
+        match unsafe { &mut *shared.side.get() } {
+            Side::Capture(writer) => {
+                for (i, frame) in samples.chunks_exact_mut(channels).enumerate() {
+                    for (c, s) in frame.iter_mut().enumerate() {
+                        let value = unsafe { *buffers[c].add(start + i) };
+                        *s = (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                    }
+                }
+                // if the encoder thread fell behind, the newest audio is dropped
+                writer.push(samples);
+            }
+            Side::Playback(reader) => {
+                let filled = reader.pop(samples);
+                samples[filled..].fill(0);
+                for (i, frame) in samples.chunks_exact(channels).enumerate() {
+                    for (c, &s) in frame.iter().enumerate() {
+                        unsafe { *buffers[c].add(start + i) = s as f32 / i16::MAX as f32 };
+                    }
+                }
+            }
+        }
+        start += len;
+    }
+    0
+}
+
+pub struct JackProducer {
+    client: Client,
+    samples: Vec<i16>,
+    reader: RingReader,
+}
+
+impl JackProducer {
+    pub fn new(settings: &BackendSettings, channels: usize) -> Result<Self, ErrorKind> {
+        let capacity = SAMPLE_RATE as usize * MAX_FRAME_MS / 1000 * channels * 4;
+        let (writer, reader) = ring(capacity);
+        Ok(JackProducer {
+            client: Client::open(settings, channels, Side::Capture(writer))?,
+            samples: Vec::new(),
+            reader,
+        })
+    }
+}
+
+impl AudioProducer for JackProducer {
+    fn produce(&mut self, frame: &mut AudioFrame) -> Result<(), ErrorKind> {
+        let needed = frame.samples().len();
+        let started = Instant::now();
+        while self.reader.available() < needed {
+            if self.client.check().is_err() || started.elapsed() > STALL_TIMEOUT {
+                return Err(ErrorKind::ReadError);
+            }
+            sleep(Duration::from_millis(1));
+        }
+        self.samples.resize(needed, 0);
+        self.reader.pop(&mut self.samples);
+        frame.fill_i16(self.samples.iter().copied());
+        Ok(())
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.client
+            .latency(self.reader.available(), JACK_CAPTURE_LATENCY)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.client.rate
+    }
+}
+
+pub struct JackConsumer {
+    client: Client,
+    samples: Vec<i16>,
+    writer: RingWriter,
+    capacity: usize,
+}
+
+impl JackConsumer {
+    pub fn new(settings: &BackendSettings) -> Result<Self, ErrorKind> {
+        // the same three frames of headroom the pulseaudio stream asks for
+        let capacity = crate::BUF_SIZE as usize * 3 / 2;
+        let (writer, reader) = ring(capacity);
+        Ok(JackConsumer {
+            client: Client::open(settings, CHANNELS, Side::Playback(reader))?,
+            samples: Vec::new(),
+            writer,
+            capacity,
+        })
+    }
+}
+
+impl Consumer for JackConsumer {
+    fn consume(&mut self, frame: &AudioFrame) -> Result<usize, ErrorKind> {
+        self.samples.clear();
+        self.samples.extend(frame.iter_i16());
+        let mut done = 0;
+        let started = Instant::now();
+        while done < self.samples.len() {
+            self.client.check().map_err(ErrorKind::WriteError)?;
+            done += self.writer.push(&self.samples[done..]);
+            if done < self.samples.len() {
+                if started.elapsed() > STALL_TIMEOUT {
+                    return Err(ErrorKind::WriteError(
+                        "jack stopped pulling audio".to_string(),
+                    ));
+                }
+                sleep(Duration::from_millis(1));
+            }
+        }
+        Ok(frame.frames())
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.client
+            .latency(self.capacity - self.writer.free(), JACK_PLAYBACK_LATENCY)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.client.rate
+    }
+}
+
+fn cstring(value: &str) -> Result<CString, ErrorKind> {
+    CString::new(value)
+        .map_err(|_| ErrorKind::ConfigError(format!("{:?} contains a NUL byte", value)))
+}
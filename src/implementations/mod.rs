@@ -1 +1,366 @@
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::{AudioProducer, CHANNELS, Consumer, ErrorKind, FRAME_SIZE, SAMPLE_RATE};
+
+#[cfg(feature = "alsa")]
+pub mod alsa;
+#[cfg(feature = "jack")]
+pub mod jack;
+pub mod null;
+#[cfg(feature = "pipewire")]
+pub mod pipewire;
 pub mod pulseaudio;
+mod resample;
+#[cfg(any(feature = "pipewire", feature = "jack"))]
+mod ring;
+pub mod tone;
+pub mod wav;
+
+pub use pulseaudio::{Device, DeviceList};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    PulseAudio,
+    /// Talks to ALSA directly, for machines without a sound server
+    Alsa,
+    /// Native PipeWire nodes instead of the pulse compatibility layer
+    PipeWire,
+    /// JACK clients with one port per channel
+    Jack,
+    /// Records silence and throws away what is played
+    Null,
+    /// Records a beeping sine tone, plays nowhere
+    Tone,
+    /// Records from `wav_input` and plays into `wav_output`
+    Wav,
+}
+
+// what the sink `share = virtual` creates is called
+const SHARE_SINK_NAME: &str = "kop-audio-share";
+
+/// Where the application audio we share comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShareSource {
+    /// A pulse source, usually the monitor of the sink a game or video plays to
+    Device(String),
+    /// A sink we create for the call, applications moved to it are only heard by the others
+    VirtualSink,
+}
+
+#[derive(Debug, Clone)]
+pub struct BackendSettings {
+    pub backend: Backend,
+    /// Source and sink names for the pulse backend, see `--list-devices`. `None` follows
+    /// the desktop default.
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    /// Application audio sent as a second stream, `None` shares nothing
+    pub share_source: Option<ShareSource>,
+    pub tone_frequency: f32,
+    pub wav_input: Option<PathBuf>,
+    pub wav_output: Option<PathBuf>,
+    pub alsa_device: String,
+    /// Period and buffer size in frames, the device may round them
+    pub alsa_period: u32,
+    pub alsa_buffer: u32,
+    /// Our nodes are called `<name>-input` and `<name>-output` in the graph
+    pub pipewire_node_name: String,
+    /// Frames per graph cycle we ask for
+    pub pipewire_quantum: u32,
+    /// Our clients are called `<name>-input` and `<name>-output`
+    pub jack_client_name: String,
+    /// Connect our ports to the physical ones once the client is up
+    pub jack_autoconnect: bool,
+}
+
+impl Default for BackendSettings {
+    fn default() -> Self {
+        BackendSettings {
+            backend: Backend::PulseAudio,
+            input_device: None,
+            output_device: None,
+            share_source: None,
+            tone_frequency: 440.0,
+            wav_input: None,
+            wav_output: None,
+            alsa_device: "default".to_string(),
+            alsa_period: 480,
+            alsa_buffer: 480 * 4,
+            pipewire_node_name: "kop-audio".to_string(),
+            pipewire_quantum: 480,
+            jack_client_name: "kop-audio".to_string(),
+            jack_autoconnect: true,
+        }
+    }
+}
+
+impl BackendSettings {
+    pub fn set_backend(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.backend = match value {
+            "pulse" | "pulseaudio" => Backend::PulseAudio,
+            "alsa" => Backend::Alsa,
+            "pipewire" => Backend::PipeWire,
+            "jack" => Backend::Jack,
+            "null" => Backend::Null,
+            "tone" => Backend::Tone,
+            "wav" => Backend::Wav,
+            _ => {
+                return Err(ErrorKind::ConfigError(format!(
+                    "backend must be pulse, alsa, pipewire, jack, null, tone or wav, got {}",
+                    value
+                )));
+            }
+        };
+        Ok(())
+    }
+
+    pub fn set_share_source(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.share_source = match value {
+            "" => {
+                return Err(ErrorKind::ConfigError(
+                    "share needs a source name or virtual".to_string(),
+                ));
+            }
+            "virtual" => Some(ShareSource::VirtualSink),
+            name => Some(ShareSource::Device(name.to_string())),
+        };
+        Ok(())
+    }
+
+    pub fn set_tone_frequency(&mut self, value: &str) -> Result<(), ErrorKind> {
+        match value.parse::<f32>() {
+            Ok(frequency) if (20.0..=20_000.0).contains(&frequency) => {
+                self.tone_frequency = frequency;
+                Ok(())
+            }
+            _ => Err(ErrorKind::ConfigError(format!(
+                "tone frequency must be between 20 and 20000 Hz, got {}",
+                value
+            ))),
+        }
+    }
+
+    pub fn set_pipewire_quantum(&mut self, value: &str) -> Result<(), ErrorKind> {
+        // anything above 20ms would be longer than our playback frames
+        match value.parse::<u32>() {
+            Ok(frames) if (32..=FRAME_SIZE as u32).contains(&frames) => {
+                self.pipewire_quantum = frames;
+                Ok(())
+            }
+            _ => Err(ErrorKind::ConfigError(format!(
+                "pipewire quantum must be between 32 and {} frames, got {}",
+                FRAME_SIZE, value
+            ))),
+        }
+    }
+
+    pub fn set_alsa_period(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.alsa_period = parse_frames(value, "alsa period")?;
+        Ok(())
+    }
+
+    pub fn set_alsa_buffer(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.alsa_buffer = parse_frames(value, "alsa buffer")?;
+        Ok(())
+    }
+
+    /// Catches options the chosen backend would silently ignore
+    pub fn validate(&self) -> Result<(), ErrorKind> {
+        if self.backend != Backend::PulseAudio
+            && (self.input_device.is_some() || self.output_device.is_some())
+        {
+            return Err(ErrorKind::ConfigError(
+                "input_device and output_device only work with the pulse backend".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Opens the capture side of the configured backend. `fragsize` is the number of bytes
+/// read at once, one encoder frame. Devices that don't record at `SAMPLE_RATE` get a
+/// resampler in front of them.
+pub fn open_producer(
+    settings: &BackendSettings,
+    channels: usize,
+    fragsize: u32,
+) -> Result<Box<dyn AudioProducer + Send>, ErrorKind> {
+    let producer: Box<dyn AudioProducer + Send> = match settings.backend {
+        Backend::PulseAudio => Box::new(pulseaudio::PulseAudioProducer::new(
+            channels,
+            fragsize,
+            settings.input_device.as_deref(),
+        )?),
+        #[cfg(feature = "alsa")]
+        Backend::Alsa => Box::new(alsa::AlsaProducer::new(settings, channels)?),
+        #[cfg(not(feature = "alsa"))]
+        Backend::Alsa => return Err(not_built("alsa")),
+        #[cfg(feature = "pipewire")]
+        Backend::PipeWire => Box::new(pipewire::PipeWireProducer::new(settings, channels)?),
+        #[cfg(not(feature = "pipewire"))]
+        Backend::PipeWire => return Err(not_built("pipewire")),
+        #[cfg(feature = "jack")]
+        Backend::Jack => Box::new(jack::JackProducer::new(settings, channels)?),
+        #[cfg(not(feature = "jack"))]
+        Backend::Jack => return Err(not_built("jack")),
+        Backend::Null => Box::new(null::NullProducer::default()),
+        Backend::Tone => Box::new(tone::ToneProducer::new(settings.tone_frequency)),
+        Backend::Wav => {
+            let Some(path) = &settings.wav_input else {
+                return Err(ErrorKind::ConfigError(
+                    "the wav backend needs a wav_input file".to_string(),
+                ));
+            };
+            Box::new(wav::WavProducer::open(path, channels)?)
+        }
+    };
+    resampled_producer(producer, channels)
+}
+
+/// Opens the application audio to share, see [`ShareSource`]. Only the pulse backend has
+/// monitors to record from.
+pub fn open_share(
+    settings: &BackendSettings,
+    channels: usize,
+    fragsize: u32,
+) -> Result<Box<dyn AudioProducer + Send>, ErrorKind> {
+    if settings.backend != Backend::PulseAudio {
+        return Err(ErrorKind::ConfigError(
+            "only the pulse backend can share application audio".to_string(),
+        ));
+    }
+    let Some(source) = &settings.share_source else {
+        return Err(ErrorKind::ConfigError("nothing to share".to_string()));
+    };
+    let producer: Box<dyn AudioProducer + Send> = match source {
+        ShareSource::Device(name) => Box::new(pulseaudio::PulseAudioProducer::new(
+            channels,
+            fragsize,
+            Some(name),
+        )?),
+        ShareSource::VirtualSink => Box::new(pulseaudio::PulseAudioProducer::virtual_sink(
+            channels,
+            fragsize,
+            SHARE_SINK_NAME,
+        )?),
+    };
+    resampled_producer(producer, channels)
+}
+
+/// Removes the sink `share = virtual` created, call before exiting
+pub fn close_share(settings: &BackendSettings) {
+    if settings.share_source != Some(ShareSource::VirtualSink) {
+        return;
+    }
+    if let Err(e) = pulseaudio::remove_virtual_sink(SHARE_SINK_NAME) {
+        warn!("Can't remove the {} sink: {:?}", SHARE_SINK_NAME, e);
+    }
+}
+
+/// Opens the playback side of the configured backend, resampled like `open_producer`
+pub fn open_consumer(settings: &BackendSettings) -> Result<Box<dyn Consumer + Send>, ErrorKind> {
+    let consumer: Box<dyn Consumer + Send> = match (settings.backend, &settings.wav_output) {
+        (Backend::PulseAudio, _) => Box::new(pulseaudio::PulseAudioConsumer::new(
+            settings.output_device.as_deref(),
+        )?),
+        #[cfg(feature = "alsa")]
+        (Backend::Alsa, _) => Box::new(alsa::AlsaConsumer::new(settings)?),
+        #[cfg(not(feature = "alsa"))]
+        (Backend::Alsa, _) => return Err(not_built("alsa")),
+        #[cfg(feature = "pipewire")]
+        (Backend::PipeWire, _) => Box::new(pipewire::PipeWireConsumer::new(settings)?),
+        #[cfg(not(feature = "pipewire"))]
+        (Backend::PipeWire, _) => return Err(not_built("pipewire")),
+        #[cfg(feature = "jack")]
+        (Backend::Jack, _) => Box::new(jack::JackConsumer::new(settings)?),
+        #[cfg(not(feature = "jack"))]
+        (Backend::Jack, _) => return Err(not_built("jack")),
+        (Backend::Wav, Some(path)) => Box::new(wav::WavConsumer::create(path)?),
+        _ => Box::new(null::NullConsumer),
+    };
+    if consumer.sample_rate() == SAMPLE_RATE {
+        return Ok(consumer);
+    }
+    Ok(Box::new(resample::ResampledConsumer::new(
+        consumer, CHANNELS,
+    )?))
+}
+
+/// Puts a resampler in front of producers that don't record at `SAMPLE_RATE`
+fn resampled_producer(
+    producer: Box<dyn AudioProducer + Send>,
+    channels: usize,
+) -> Result<Box<dyn AudioProducer + Send>, ErrorKind> {
+    if producer.sample_rate() == SAMPLE_RATE {
+        return Ok(producer);
+    }
+    Ok(Box::new(resample::ResampledProducer::new(
+        producer, channels,
+    )?))
+}
+
+/// The sources and sinks `input_device` and `output_device` can name
+pub fn list_devices(settings: &BackendSettings) -> Result<DeviceList, ErrorKind> {
+    match settings.backend {
+        Backend::PulseAudio => pulseaudio::list_devices(),
+        _ => Err(ErrorKind::ConfigError(
+            "only the pulse backend can pick devices".to_string(),
+        )),
+    }
+}
+
+#[cfg(not(all(feature = "alsa", feature = "pipewire", feature = "jack")))]
+fn not_built(backend: &str) -> ErrorKind {
+    ErrorKind::InitializationError2(format!(
+        "built without {} support, enable the {} feature",
+        backend, backend
+    ))
+}
+
+fn parse_frames(value: &str, name: &str) -> Result<u32, ErrorKind> {
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|frames| (32..=SAMPLE_RATE).contains(frames))
+        .ok_or_else(|| {
+            ErrorKind::ConfigError(format!(
+                "{} must be between 32 and {} frames, got {}",
+                name, SAMPLE_RATE, value
+            ))
+        })
+}
+
+/// Paces producers that don't have a sound card blocking them to real time
+struct Clock {
+    start: Instant,
+    frames: u64,
+    rate: u32,
+}
+
+impl Clock {
+    fn new(rate: u32) -> Self {
+        Clock {
+            start: Instant::now(),
+            frames: 0,
+            rate,
+        }
+    }
+
+    /// Sleeps until `frames` more samples per channel would have been recorded
+    fn wait(&mut self, frames: usize) {
+        self.frames += frames as u64;
+        let due = self.start + Duration::from_micros(self.frames * 1_000_000 / self.rate as u64);
+        let now = Instant::now();
+        if due > now {
+            sleep(due - now);
+        } else if now - due > Duration::from_millis(200) {
+            // the reader stalled, start over instead of delivering a burst of frames
+            self.start = now;
+            self.frames = 0;
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use super::Clock;
+use crate::{AudioFrame, AudioProducer, Consumer, ErrorKind, SAMPLE_RATE};
+
+pub struct NullProducer {
+    clock: Clock,
+}
+
+impl Default for NullProducer {
+    fn default() -> Self {
+        NullProducer {
+            clock: Clock::new(SAMPLE_RATE),
+        }
+    }
+}
+
+impl AudioProducer for NullProducer {
+    fn produce(&mut self, frame: &mut AudioFrame) -> Result<(), ErrorKind> {
+        frame.silence();
+        self.clock.wait(frame.frames());
+        Ok(())
+    }
+}
+
+pub struct NullConsumer;
+
+impl Consumer for NullConsumer {
+    fn consume(&mut self, frame: &AudioFrame) -> Result<usize, ErrorKind> {
+        Ok(frame.frames())
+    }
+}
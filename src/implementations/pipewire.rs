@@ -0,0 +1,539 @@
+use std::cell::UnsafeCell;
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::ptr;
+use std::sync::Once;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+
+use super::BackendSettings;
+use super::ring::{RingReader, RingWriter, ring};
+use crate::{AudioFrame, AudioProducer, CHANNELS, Consumer, ErrorKind, SAMPLE_RATE};
+
+// libpipewire-0.3 and the parts of the spa pod format we need, linked through pkg-config
+// in build.rs. The spa helpers are inline functions in the C headers, so the format pod is
+// put together by hand.
+#[repr(C)]
+struct PwStreamEvents {
+    version: u32,
+    destroy: Option<unsafe extern "C" fn(*mut c_void)>,
+    state_changed: Option<unsafe extern "C" fn(*mut c_void, c_int, c_int, *const c_char)>,
+    control_info: Option<unsafe extern "C" fn()>,
+    io_changed: Option<unsafe extern "C" fn()>,
+    param_changed: Option<unsafe extern "C" fn()>,
+    add_buffer: Option<unsafe extern "C" fn()>,
+    remove_buffer: Option<unsafe extern "C" fn()>,
+    process: Option<unsafe extern "C" fn(*mut c_void)>,
+    drained: Option<unsafe extern "C" fn()>,
+    command: Option<unsafe extern "C" fn()>,
+    trigger_done: Option<unsafe extern "C" fn()>,
+}
+
+#[repr(C)]
+struct PwBuffer {
+    buffer: *mut SpaBuffer,
+    user_data: *mut c_void,
+    size: u64,
+    requested: u64,
+}
+
+#[repr(C)]
+struct SpaBuffer {
+    n_metas: u32,
+    n_datas: u32,
+    metas: *mut c_void,
+    datas: *mut SpaData,
+}
+
+#[repr(C)]
+struct SpaData {
+    type_: u32,
+    flags: u32,
+    fd: i64,
+    mapoffset: u32,
+    maxsize: u32,
+    data: *mut c_void,
+    chunk: *mut SpaChunk,
+}
+
+#[repr(C)]
+struct SpaChunk {
+    offset: u32,
+    size: u32,
+    stride: i32,
+    flags: i32,
+}
+
+// leading part of struct pw_time, pw_stream_get_time_n only fills what we ask for
+#[repr(C)]
+#[derive(Default)]
+struct PwTime {
+    now: i64,
+    rate_num: u32,
+    rate_denom: u32,
+    ticks: u64,
+    delay: i64,
+    queued: u64,
+}
+
+unsafe extern "C" {
+    fn pw_init(argc: *mut c_int, argv: *mut *mut *mut c_char);
+    fn pw_thread_loop_new(name: *const c_char, props: *const c_void) -> *mut c_void;
+    fn pw_thread_loop_get_loop(thread_loop: *mut c_void) -> *mut c_void;
+    fn pw_thread_loop_start(thread_loop: *mut c_void) -> c_int;
+    fn pw_thread_loop_stop(thread_loop: *mut c_void);
+    fn pw_thread_loop_destroy(thread_loop: *mut c_void);
+    fn pw_thread_loop_lock(thread_loop: *mut c_void);
+    fn pw_thread_loop_unlock(thread_loop: *mut c_void);
+    fn pw_properties_new(key: *const c_char, ...) -> *mut c_void;
+    fn pw_stream_new_simple(
+        main_loop: *mut c_void,
+        name: *const c_char,
+        props: *mut c_void,
+        events: *const PwStreamEvents,
+        data: *mut c_void,
+    ) -> *mut c_void;
+    fn pw_stream_connect(
+        stream: *mut c_void,
+        direction: c_int,
+        target_id: u32,
+        flags: c_int,
+        params: *mut *const c_void,
+        n_params: u32,
+    ) -> c_int;
+    fn pw_stream_destroy(stream: *mut c_void);
+    fn pw_stream_dequeue_buffer(stream: *mut c_void) -> *mut PwBuffer;
+    fn pw_stream_queue_buffer(stream: *mut c_void, buffer: *mut PwBuffer) -> c_int;
+    fn pw_stream_get_time_n(stream: *mut c_void, time: *mut PwTime, size: usize) -> c_int;
+    fn pw_stream_state_as_string(state: c_int) -> *const c_char;
+}
+
+const PW_VERSION_STREAM_EVENTS: u32 = 2;
+const PW_ID_ANY: u32 = u32::MAX;
+const PW_STREAM_STATE_ERROR: c_int = -1;
+const PW_STREAM_FLAG_AUTOCONNECT: c_int = 1 << 0;
+const PW_STREAM_FLAG_MAP_BUFFERS: c_int = 1 << 2;
+const PW_STREAM_FLAG_RT_PROCESS: c_int = 1 << 4;
+const SPA_DIRECTION_INPUT: c_int = 0;
+const SPA_DIRECTION_OUTPUT: c_int = 1;
+
+const SPA_TYPE_ID: u32 = 3;
+const SPA_TYPE_INT: u32 = 4;
+const SPA_TYPE_ARRAY: u32 = 13;
+const SPA_TYPE_OBJECT: u32 = 15;
+const SPA_TYPE_OBJECT_FORMAT: u32 = 0x40003;
+const SPA_PARAM_ENUM_FORMAT: u32 = 3;
+const SPA_FORMAT_MEDIA_TYPE: u32 = 1;
+const SPA_FORMAT_MEDIA_SUBTYPE: u32 = 2;
+const SPA_FORMAT_AUDIO_FORMAT: u32 = 0x10001;
+const SPA_FORMAT_AUDIO_RATE: u32 = 0x10003;
+const SPA_FORMAT_AUDIO_CHANNELS: u32 = 0x10004;
+const SPA_FORMAT_AUDIO_POSITION: u32 = 0x10005;
+const SPA_MEDIA_TYPE_AUDIO: u32 = 1;
+const SPA_MEDIA_SUBTYPE_RAW: u32 = 1;
+#[cfg(target_endian = "little")]
+const SPA_AUDIO_FORMAT_S16: u32 = 0x103;
+#[cfg(target_endian = "big")]
+const SPA_AUDIO_FORMAT_S16: u32 = 0x104;
+const SPA_AUDIO_CHANNEL_MONO: u32 = 2;
+const SPA_AUDIO_CHANNEL_FL: u32 = 3;
+const SPA_AUDIO_CHANNEL_FR: u32 = 4;
+
+// how long produce and consume wait for the graph before giving up
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+// largest encoder frame, the capture queue holds a few of them
+const MAX_FRAME_MS: usize = 60;
+
+static INIT: Once = Once::new();
+
+enum Side {
+    Capture(RingWriter),
+    Playback(RingReader),
+}
+
+/// State the process callback works on, lives in a box so its address stays put
+struct Shared {
+    stream: *mut c_void,
+    channels: usize,
+    // only ever touched by the process callback
+    side: UnsafeCell<Side>,
+    failed: AtomicBool,
+}
+
+/// A stream on its own pipewire thread loop
+struct Stream {
+    thread_loop: *mut c_void,
+    shared: Box<Shared>,
+}
+
+// the stream pointers are only used under the thread loop lock or through calls that
+// pipewire documents as safe from any thread
+unsafe impl Send for Stream {}
+
+static EVENTS: PwStreamEvents = PwStreamEvents {
+    version: PW_VERSION_STREAM_EVENTS,
+    destroy: None,
+    state_changed: Some(on_state_changed),
+    control_info: None,
+    io_changed: None,
+    param_changed: None,
+    add_buffer: None,
+    remove_buffer: None,
+    process: Some(on_process),
+    drained: None,
+    command: None,
+    trigger_done: None,
+};
+
+impl Stream {
+    fn open(settings: &BackendSettings, channels: usize, side: Side) -> Result<Self, ErrorKind> {
+        INIT.call_once(|| unsafe { pw_init(ptr::null_mut(), ptr::null_mut()) });
+        let capture = matches!(side, Side::Capture(_));
+        let (suffix, category, direction) = if capture {
+            ("input", "Capture", SPA_DIRECTION_INPUT)
+        } else {
+            ("output", "Playback", SPA_DIRECTION_OUTPUT)
+        };
+        let node_name = format!("{}-{}", settings.pipewire_node_name, suffix);
+        let props = [
+            ("media.type", "Audio".to_string()),
+            ("media.category", category.to_string()),
+            ("media.role", "Communication".to_string()),
+            ("application.name", "kop-audio".to_string()),
+            ("node.name", node_name.clone()),
+            (
+                "node.description",
+                format!("{} {}", settings.pipewire_node_name, suffix),
+            ),
+            (
+                "node.latency",
+                format!("{}/{}", settings.pipewire_quantum, SAMPLE_RATE),
+            ),
+        ]
+        .into_iter()
+        .map(|(k, v)| cstring(k).and_then(|k| Ok((k, cstring(&v)?))))
+        .collect::<Result<Vec<_>, _>>()?;
+        let name = cstring(&node_name)?;
+
+        let thread_loop = unsafe { pw_thread_loop_new(name.as_ptr(), ptr::null()) };
+        if thread_loop.is_null() {
+            return Err(ErrorKind::InitializationError2(
+                "pipewire: can't create thread loop".to_string(),
+            ));
+        }
+        let mut stream = Stream {
+            thread_loop,
+            shared: Box::new(Shared {
+                stream: ptr::null_mut(),
+                channels,
+                side: UnsafeCell::new(side),
+                failed: AtomicBool::new(false),
+            }),
+        };
+        if unsafe { pw_thread_loop_start(thread_loop) } < 0 {
+            return Err(ErrorKind::InitializationError2(
+                "pipewire: can't start thread loop".to_string(),
+            ));
+        }
+
+        let pod = audio_format_pod(channels);
+        let mut params = [pod.as_ptr() as *const c_void];
+        unsafe {
+            pw_thread_loop_lock(thread_loop);
+            let p = &props;
+            let properties = pw_properties_new(
+                p[0].0.as_ptr(),
+                p[0].1.as_ptr(),
+                p[1].0.as_ptr(),
+                p[1].1.as_ptr(),
+                p[2].0.as_ptr(),
+                p[2].1.as_ptr(),
+                p[3].0.as_ptr(),
+                p[3].1.as_ptr(),
+                p[4].0.as_ptr(),
+                p[4].1.as_ptr(),
+                p[5].0.as_ptr(),
+                p[5].1.as_ptr(),
+                p[6].0.as_ptr(),
+                p[6].1.as_ptr(),
+                ptr::null::<c_char>(),
+            );
+            let shared: *mut Shared = &mut *stream.shared;
+            let handle = pw_stream_new_simple(
+                pw_thread_loop_get_loop(thread_loop),
+                name.as_ptr(),
+                properties,
+                &EVENTS,
+                shared as *mut c_void,
+            );
+            stream.shared.stream = handle;
+            let result = if handle.is_null() {
+                -1
+            } else {
+                pw_stream_connect(
+                    handle,
+                    direction,
+                    PW_ID_ANY,
+                    PW_STREAM_FLAG_AUTOCONNECT
+                        | PW_STREAM_FLAG_MAP_BUFFERS
+                        | PW_STREAM_FLAG_RT_PROCESS,
+                    params.as_mut_ptr(),
+                    params.len() as u32,
+                )
+            };
+            pw_thread_loop_unlock(thread_loop);
+            if result < 0 {
+                return Err(ErrorKind::InitializationError2(format!(
+                    "pipewire: can't connect stream {}",
+                    node_name
+                )));
+            }
+        }
+        info!(
+            "Opened pipewire node {} with {} channels, quantum {} frames",
+            node_name, channels, settings.pipewire_quantum
+        );
+        Ok(stream)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.shared.failed.load(Ordering::Relaxed) {
+            return Err("pipewire stream failed".to_string());
+        }
+        Ok(())
+    }
+
+    /// Delay between the graph and the device plus what sits in our queue
+    fn latency(&self, queued: usize) -> Option<Duration> {
+        let mut time = PwTime::default();
+        let result =
+            unsafe { pw_stream_get_time_n(self.shared.stream, &mut time, size_of::<PwTime>()) };
+        if result < 0 || time.rate_denom == 0 {
+            return None;
+        }
+        let device_us =
+            time.delay.max(0) as u64 * time.rate_num as u64 * 1_000_000 / time.rate_denom as u64;
+        let queued_us = (queued / self.shared.channels) as u64 * 1_000_000 / SAMPLE_RATE as u64;
+        Some(Duration::from_micros(device_us + queued_us))
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        unsafe {
+            pw_thread_loop_lock(self.thread_loop);
+            if !self.shared.stream.is_null() {
+                pw_stream_destroy(self.shared.stream);
+            }
+            pw_thread_loop_unlock(self.thread_loop);
+            pw_thread_loop_stop(self.thread_loop);
+            pw_thread_loop_destroy(self.thread_loop);
+        }
+    }
+}
+
+unsafe extern "C" fn on_state_changed(
+    data: *mut c_void,
+    _old: c_int,
+    state: c_int,
+    message: *const c_char,
+) {
+    let shared = unsafe { &*(data as *const Shared) };
+    let state_name = unsafe { CStr::from_ptr(pw_stream_state_as_string(state)) };
+    info!("Pipewire stream is {}", state_name.to_string_lossy());
+    if state == PW_STREAM_STATE_ERROR {
+        if !message.is_null() {
+            let message = unsafe { CStr::from_ptr(message) };
+            error!("Pipewire stream error: {}", message.to_string_lossy());
+        }
+        shared.failed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Runs on the pipewire data thread once per quantum
+unsafe extern "C" fn on_process(data: *mut c_void) {
+    let shared = unsafe { &*(data as *const Shared) };
+    let buffer = unsafe { pw_stream_dequeue_buffer(shared.stream) };
+    if buffer.is_null() {
+        return;
+    }
+    unsafe {
+        let spa_buffer = &*(*buffer).buffer;
+        if spa_buffer.n_datas > 0
+            && !(*spa_buffer.datas).data.is_null()
+            && (*spa_buffer.datas).maxsize > 0
+        {
+            let spa_data = &mut *spa_buffer.datas;
+            let chunk = &mut *spa_data.chunk;
+            let stride = shared.channels * 2;
+            match &mut *shared.side.get() {
+                Side::Capture(writer) => {
+                    let offset = (chunk.offset % spa_data.maxsize) as usize;
+                    let len = (chunk.size as usize).min(spa_data.maxsize as usize - offset);
+                    let samples = std::slice::from_raw_parts(
+                        (spa_data.data as *const u8).add(offset) as *const i16,
+                        len / 2,
+                    );
+                    // if the encoder thread fell behind, the newest audio is dropped
+                    writer.push(samples);
+                }
+                Side::Playback(reader) => {
+                    let mut frames = spa_data.maxsize as usize / stride;
+                    if (*buffer).requested > 0 {
+                        frames = frames.min((*buffer).requested as usize);
+                    }
+                    let samples = std::slice::from_raw_parts_mut(
+                        spa_data.data as *mut i16,
+                        frames * shared.channels,
+                    );
+                    let filled = reader.pop(samples);
+                    samples[filled..].fill(0);
+                    chunk.offset = 0;
+                    chunk.stride = stride as i32;
+                    chunk.size = (frames * stride) as u32;
+                }
+            }
+        }
+        pw_stream_queue_buffer(shared.stream, buffer);
+    }
+}
+
+pub struct PipeWireProducer {
+    stream: Stream,
+    samples: Vec<i16>,
+    reader: RingReader,
+}
+
+impl PipeWireProducer {
+    pub fn new(settings: &BackendSettings, channels: usize) -> Result<Self, ErrorKind> {
+        let capacity = SAMPLE_RATE as usize * MAX_FRAME_MS / 1000 * channels * 4;
+        let (writer, reader) = ring(capacity);
+        Ok(PipeWireProducer {
+            stream: Stream::open(settings, channels, Side::Capture(writer))?,
+            samples: Vec::new(),
+            reader,
+        })
+    }
+}
+
+impl AudioProducer for PipeWireProducer {
+    fn produce(&mut self, frame: &mut AudioFrame) -> Result<(), ErrorKind> {
+        let needed = frame.samples().len();
+        let started = Instant::now();
+        while self.reader.available() < needed {
+            if self.stream.check().is_err() || started.elapsed() > STALL_TIMEOUT {
+                return Err(ErrorKind::ReadError);
+            }
+            sleep(Duration::from_millis(1));
+        }
+        self.samples.resize(needed, 0);
+        self.reader.pop(&mut self.samples);
+        frame.fill_i16(self.samples.iter().copied());
+        Ok(())
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.stream.latency(self.reader.available())
+    }
+}
+
+pub struct PipeWireConsumer {
+    stream: Stream,
+    samples: Vec<i16>,
+    writer: RingWriter,
+    capacity: usize,
+}
+
+impl PipeWireConsumer {
+    pub fn new(settings: &BackendSettings) -> Result<Self, ErrorKind> {
+        // the same three frames of headroom the pulseaudio stream asks for
+        let capacity = crate::BUF_SIZE as usize * 3 / 2;
+        let (writer, reader) = ring(capacity);
+        Ok(PipeWireConsumer {
+            stream: Stream::open(settings, CHANNELS, Side::Playback(reader))?,
+            samples: Vec::new(),
+            writer,
+            capacity,
+        })
+    }
+}
+
+impl Consumer for PipeWireConsumer {
+    fn consume(&mut self, frame: &AudioFrame) -> Result<usize, ErrorKind> {
+        self.samples.clear();
+        self.samples.extend(frame.iter_i16());
+        let mut done = 0;
+        let started = Instant::now();
+        while done < self.samples.len() {
+            self.stream.check().map_err(ErrorKind::WriteError)?;
+            done += self.writer.push(&self.samples[done..]);
+            if done < self.samples.len() {
+                if started.elapsed() > STALL_TIMEOUT {
+                    return Err(ErrorKind::WriteError(
+                        "pipewire graph stopped pulling audio".to_string(),
+                    ));
+                }
+                sleep(Duration::from_millis(1));
+            }
+        }
+        Ok(frame.frames())
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.stream.latency(self.capacity - self.writer.free())
+    }
+}
+
+fn cstring(value: &str) -> Result<CString, ErrorKind> {
+    CString::new(value)
+        .map_err(|_| ErrorKind::ConfigError(format!("{:?} contains a NUL byte", value)))
+}
+
+/// EnumFormat pod for interleaved native endian 16 bit audio at our sample rate
+fn audio_format_pod(channels: usize) -> Vec<u64> {
+    let positions: &[u32] = if channels == 1 {
+        &[SPA_AUDIO_CHANNEL_MONO]
+    } else {
+        &[SPA_AUDIO_CHANNEL_FL, SPA_AUDIO_CHANNEL_FR]
+    };
+    let mut words: Vec<u32> = vec![
+        0,
+        SPA_TYPE_OBJECT,
+        SPA_TYPE_OBJECT_FORMAT,
+        SPA_PARAM_ENUM_FORMAT,
+    ];
+    // every property is key, flags, then a pod padded to 8 bytes
+    let mut property = |key: u32, value_type: u32, value: u32| {
+        words.extend([key, 0, 4, value_type, value, 0]);
+    };
+    property(SPA_FORMAT_MEDIA_TYPE, SPA_TYPE_ID, SPA_MEDIA_TYPE_AUDIO);
+    property(SPA_FORMAT_MEDIA_SUBTYPE, SPA_TYPE_ID, SPA_MEDIA_SUBTYPE_RAW);
+    property(SPA_FORMAT_AUDIO_FORMAT, SPA_TYPE_ID, SPA_AUDIO_FORMAT_S16);
+    property(SPA_FORMAT_AUDIO_RATE, SPA_TYPE_INT, SAMPLE_RATE);
+    property(SPA_FORMAT_AUDIO_CHANNELS, SPA_TYPE_INT, channels as u32);
+    words.extend([
+        SPA_FORMAT_AUDIO_POSITION,
+        0,
+        8 + 4 * positions.len() as u32,
+        SPA_TYPE_ARRAY,
+        4,
+        SPA_TYPE_ID,
+    ]);
+    words.extend_from_slice(positions);
+    if !words.len().is_multiple_of(2) {
+        words.push(0);
+    }
+    // the object size doesn't count its own 8 byte header
+    words[0] = (words.len() as u32 - 2) * 4;
+    words
+        .chunks_exact(2)
+        .map(|pair| {
+            let mut bytes = [0u8; 8];
+            bytes[..4].copy_from_slice(&pair[0].to_ne_bytes());
+            bytes[4..].copy_from_slice(&pair[1].to_ne_bytes());
+            u64::from_ne_bytes(bytes)
+        })
+        .collect()
+}
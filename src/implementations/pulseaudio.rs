@@ -1,97 +1,361 @@
-use crate::{AudioProducer, BUF_SIZE, CHANNELS, Consumer, SAMPLE_RATE};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use log::{info, warn};
+
+use crate::{AudioFrame, AudioProducer, BUF_SIZE, CHANNELS, Consumer, SAMPLE_RATE};
 
 use crate::ErrorKind;
 use crate::psimple::Simple;
-use crate::pulse::def::BufferAttr;
+use crate::pulse::callbacks::ListResult;
+use crate::pulse::context::{Context, FlagSet, State};
+use crate::pulse::def::{BufferAttr, INVALID_INDEX};
+use crate::pulse::mainloop::standard::{IterateResult, Mainloop};
+use crate::pulse::operation::{Operation, State as OperationState};
 use crate::pulse::sample::{Format, Spec};
 use crate::pulse::stream::Direction;
 
 pub struct PulseAudioProducer {
     endpoint: Simple,
+    // the sink we record the monitor of, unloaded once the stream is closed
+    virtual_sink: Option<VirtualSink>,
+    // native endian 16 bit samples as the stream delivers them
+    buffer: Vec<u8>,
+    rate: u32,
 }
 
 impl PulseAudioProducer {
-    pub fn new() -> Result<Self, ErrorKind> {
+    /// `fragsize` is the number of bytes we read at once, one encoder frame. `device` is the
+    /// name of a source, `None` records from the default one. The stream runs at the rate
+    /// of the source so the server doesn't resample on top of us.
+    pub fn new(channels: usize, fragsize: u32, device: Option<&str>) -> Result<Self, ErrorKind> {
+        let rate = native_rate(device, |list| {
+            list.sources.into_iter().chain(list.monitors).collect()
+        });
         let spec = Spec {
             format: Format::S16NE,
-            channels: CHANNELS as u8,
-            rate: SAMPLE_RATE,
+            channels: channels as u8,
+            rate,
         };
+        let fragsize = (fragsize as u64 * rate as u64 / SAMPLE_RATE as u64) as u32;
         let record_attr = BufferAttr {
             maxlength: u32::MAX, // maximum length of the buffer
             tlength: u32::MAX,   // playback-only: target length of the buffer
             prebuf: u32::MAX,    // playback-only: prebuffering size
             minreq: u32::MAX,    // minimum request size
-            fragsize: BUF_SIZE,  // record-only: fragment size
+            fragsize,            // record-only: fragment size
         };
 
         let rec = Simple::new(
             None,                 // Use the default server
             "Rustaudio Recorder", // Our application’s name
             Direction::Record,    // We want a recording stream
-            None,                 // Use the default device
+            device,               // The source to record from
             "Record",             // Description of our stream
             &spec,                // Our sample format
             None,                 // Use default channel map
             Some(&record_attr),   // Use default buffering attributes
         );
         match rec {
-            Ok(endpoint) => Ok(PulseAudioProducer { endpoint }),
+            Ok(endpoint) => Ok(PulseAudioProducer {
+                endpoint,
+                virtual_sink: None,
+                buffer: Vec::new(),
+                rate,
+            }),
             Err(_) => Err(ErrorKind::InitializationError),
         }
     }
+
+    /// Creates a sink called `name` that plays nowhere and records what applications play
+    /// into it. The sink is gone again when the producer is dropped.
+    pub fn virtual_sink(channels: usize, fragsize: u32, name: &str) -> Result<Self, ErrorKind> {
+        let sink = VirtualSink::load(name)?;
+        let mut producer = Self::new(channels, fragsize, Some(&format!("{}.monitor", name)))?;
+        producer.virtual_sink = Some(sink);
+        Ok(producer)
+    }
 }
 
 impl AudioProducer for PulseAudioProducer {
-    fn produce(&mut self, data: &mut [u8]) -> Result<(), ErrorKind> {
-        match self.endpoint.read(data) {
-            Ok(_) => Ok(()),
+    fn produce(&mut self, frame: &mut AudioFrame) -> Result<(), ErrorKind> {
+        self.buffer.resize(frame.samples().len() * 2, 0);
+        match self.endpoint.read(&mut self.buffer) {
+            Ok(_) => {
+                frame.fill_i16(
+                    self.buffer
+                        .chunks_exact(2)
+                        .map(|b| i16::from_ne_bytes([b[0], b[1]])),
+                );
+                Ok(())
+            }
             Err(_) => Err(ErrorKind::ReadError),
         }
     }
+
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
 }
 
 pub struct PulseAudioConsumer {
     endpoint: Simple,
+    buffer: Vec<u8>,
+    rate: u32,
 }
 
 impl PulseAudioConsumer {
-    pub fn new() -> Result<Self, ErrorKind> {
+    /// `device` is the name of a sink, `None` plays to the default one. Like the producer
+    /// it runs at the rate of the sink.
+    pub fn new(device: Option<&str>) -> Result<Self, ErrorKind> {
+        let rate = native_rate(device, |list| list.sinks);
         let spec = Spec {
             format: Format::S16NE,
             channels: CHANNELS as u8,
-            rate: SAMPLE_RATE,
+            rate,
         };
+        // one frame at the sink's rate
+        let frame = (BUF_SIZE as u64 * rate as u64 / SAMPLE_RATE as u64) as u32;
         let playback_attr = BufferAttr {
-            maxlength: u32::MAX,   // maximum length of the buffer
-            tlength: BUF_SIZE * 3, // playback-only: target length of the buffer
-            prebuf: BUF_SIZE * 2,  // playback-only: prebuffering size
-            minreq: BUF_SIZE,      // minimum request size
-            fragsize: u32::MAX,    // record-only: fragment size
+            maxlength: u32::MAX, // maximum length of the buffer
+            tlength: frame * 3,  // playback-only: target length of the buffer
+            prebuf: frame * 2,   // playback-only: prebuffering size
+            minreq: frame,       // minimum request size
+            fragsize: u32::MAX,  // record-only: fragment size
         };
 
         let out = Simple::new(
             None,
             "Rustaudio Player",
             Direction::Playback,
-            None,
+            device,
             "Play",
             &spec,
             None,
             Some(&playback_attr),
         );
         match out {
-            Ok(endpoint) => Ok(PulseAudioConsumer { endpoint }),
+            Ok(endpoint) => Ok(PulseAudioConsumer {
+                endpoint,
+                buffer: Vec::new(),
+                rate,
+            }),
             Err(_) => Err(ErrorKind::InitializationError),
         }
     }
 }
 
 impl Consumer for PulseAudioConsumer {
-    fn consume(&mut self, data: &[u8]) -> Result<usize, ErrorKind> {
-        match self.endpoint.write(data) {
-            Ok(_) => Ok(data.len()),
+    fn consume(&mut self, frame: &AudioFrame) -> Result<usize, ErrorKind> {
+        self.buffer.clear();
+        self.buffer
+            .extend(frame.iter_i16().flat_map(i16::to_ne_bytes));
+        match self.endpoint.write(&self.buffer) {
+            Ok(_) => Ok(frame.frames()),
             Err(e) => Err(ErrorKind::WriteError(format!("{:?}", e))),
         }
     }
+
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+}
+
+/// A source or sink as the sound server knows it
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// What `--input-device` and `--output-device` take
+    pub name: String,
+    pub description: String,
+    pub is_default: bool,
+    /// Rate the device runs at
+    pub rate: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DeviceList {
+    pub sources: Vec<Device>,
+    /// Monitors of sinks, they record what a sink plays
+    pub monitors: Vec<Device>,
+    pub sinks: Vec<Device>,
+}
+
+/// Asks the sound server which sources and sinks it has
+pub fn list_devices() -> Result<DeviceList, ErrorKind> {
+    let (mut mainloop, mut context) = connect("Rustaudio Devices")?;
+    let introspector = context.introspect();
+    let defaults = Rc::new(RefCell::new((None, None)));
+    let list = Rc::new(RefCell::new(DeviceList::default()));
+
+    let result = defaults.clone();
+    let operation = introspector.get_server_info(move |info| {
+        *result.borrow_mut() = (
+            info.default_source_name
+                .as_ref()
+                .map(|name| name.to_string()),
+            info.default_sink_name.as_ref().map(|name| name.to_string()),
+        );
+    });
+    wait_for(&mut mainloop, operation)?;
+
+    let result = list.clone();
+    let operation = introspector.get_source_info_list(move |item| {
+        if let ListResult::Item(info) = item {
+            let device = Device {
+                name: info.name.as_deref().unwrap_or_default().to_string(),
+                description: info.description.as_deref().unwrap_or_default().to_string(),
+                is_default: false,
+                rate: info.sample_spec.rate,
+            };
+            let mut list = result.borrow_mut();
+            if info.monitor_of_sink.is_some() {
+                list.monitors.push(device);
+            } else {
+                list.sources.push(device);
+            }
+        }
+    });
+    wait_for(&mut mainloop, operation)?;
+
+    let result = list.clone();
+    let operation = introspector.get_sink_info_list(move |item| {
+        if let ListResult::Item(info) = item {
+            result.borrow_mut().sinks.push(Device {
+                name: info.name.as_deref().unwrap_or_default().to_string(),
+                description: info.description.as_deref().unwrap_or_default().to_string(),
+                is_default: false,
+                rate: info.sample_spec.rate,
+            });
+        }
+    });
+    wait_for(&mut mainloop, operation)?;
+    context.disconnect();
+
+    let (default_source, default_sink) = defaults.take();
+    let mut list = list.take();
+    for device in list.sources.iter_mut().chain(list.monitors.iter_mut()) {
+        device.is_default = default_source.as_deref() == Some(device.name.as_str());
+    }
+    for device in list.sinks.iter_mut() {
+        device.is_default = default_sink.as_deref() == Some(device.name.as_str());
+    }
+    Ok(list)
+}
+
+/// Rate of the named or default device in `devices`, ours if the server can't tell
+fn native_rate(device: Option<&str>, devices: impl FnOnce(DeviceList) -> Vec<Device>) -> u32 {
+    let Ok(list) = list_devices() else {
+        return SAMPLE_RATE;
+    };
+    devices(list)
+        .into_iter()
+        .find(|d| device.map_or(d.is_default, |name| d.name == name))
+        .map_or(SAMPLE_RATE, |d| d.rate)
+}
+
+/// A null sink we loaded into the sound server
+struct VirtualSink {
+    module: u32,
+}
+
+impl VirtualSink {
+    fn load(name: &str) -> Result<Self, ErrorKind> {
+        // a sink of ours that outlived a crashed or killed client
+        remove_virtual_sink(name)?;
+        let (mut mainloop, mut context) = connect("Rustaudio Share")?;
+        let module = Rc::new(RefCell::new(INVALID_INDEX));
+        let result = module.clone();
+        let operation = context.introspect().load_module(
+            "module-null-sink",
+            &format!(
+                "sink_name={} sink_properties=device.description={}",
+                name, name
+            ),
+            move |index| *result.borrow_mut() = index,
+        );
+        wait_for(&mut mainloop, operation)?;
+        context.disconnect();
+        let module = module.take();
+        if module == INVALID_INDEX {
+            return Err(ErrorKind::InitializationError2(format!(
+                "pulseaudio: can't create the {} sink",
+                name
+            )));
+        }
+        info!("Created sink {}, play what you want to share into it", name);
+        Ok(VirtualSink { module })
+    }
+}
+
+impl Drop for VirtualSink {
+    fn drop(&mut self) {
+        let Ok((mut mainloop, mut context)) = connect("Rustaudio Share") else {
+            warn!("Can't remove our share sink, the sound server is gone");
+            return;
+        };
+        let operation = context.introspect().unload_module(self.module, |_| {});
+        let _ = wait_for(&mut mainloop, operation);
+        context.disconnect();
+    }
+}
+
+/// Unloads the module that owns the sink `name`, if there is one. The share thread holds
+/// our `VirtualSink` and isn't joined on exit, so its `Drop` can't be relied on.
+pub fn remove_virtual_sink(name: &str) -> Result<(), ErrorKind> {
+    let (mut mainloop, mut context) = connect("Rustaudio Share")?;
+    let owner = Rc::new(RefCell::new(None));
+    let result = owner.clone();
+    let operation = context
+        .introspect()
+        .get_sink_info_by_name(name, move |item| {
+            if let ListResult::Item(sink) = item {
+                *result.borrow_mut() = sink.owner_module;
+            }
+        });
+    wait_for(&mut mainloop, operation)?;
+    if let Some(module) = owner.take() {
+        info!("Removing the {} sink", name);
+        let operation = context.introspect().unload_module(module, |_| {});
+        wait_for(&mut mainloop, operation)?;
+    }
+    context.disconnect();
+    Ok(())
+}
+
+/// Opens a control connection to the sound server
+fn connect(name: &str) -> Result<(Mainloop, Context), ErrorKind> {
+    let mut mainloop = Mainloop::new().ok_or(ErrorKind::InitializationError)?;
+    let mut context = Context::new(&mainloop, name).ok_or(ErrorKind::InitializationError)?;
+    context
+        .connect(None, FlagSet::NOAUTOSPAWN, None)
+        .map_err(|e| ErrorKind::InitializationError2(format!("pulseaudio: {}", e)))?;
+    loop {
+        iterate(&mut mainloop)?;
+        match context.get_state() {
+            State::Ready => return Ok((mainloop, context)),
+            State::Failed | State::Terminated => {
+                return Err(ErrorKind::InitializationError2(
+                    "pulseaudio: can't connect to the sound server".to_string(),
+                ));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Runs the mainloop until the callback of `operation` has seen everything
+fn wait_for<F: ?Sized>(mainloop: &mut Mainloop, operation: Operation<F>) -> Result<(), ErrorKind> {
+    while operation.get_state() == OperationState::Running {
+        iterate(mainloop)?;
+    }
+    Ok(())
+}
+
+fn iterate(mainloop: &mut Mainloop) -> Result<(), ErrorKind> {
+    match mainloop.iterate(true) {
+        IterateResult::Success(_) => Ok(()),
+        IterateResult::Quit(_) | IterateResult::Err(_) => Err(ErrorKind::InitializationError2(
+            "pulseaudio: the mainloop stopped".to_string(),
+        )),
+    }
 }
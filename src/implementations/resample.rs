@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use log::info;
+use rubato::{FftFixedInOut, Resampler};
+
+use crate::{AudioFrame, AudioProducer, Consumer, ErrorKind, SAMPLE_RATE};
+
+// resampler chunk, the ratio between the rates may round it a bit
+const CHUNK_MS: usize = 10;
+
+/// Converts between the rate of a device and the pipeline rate in chunks
+struct Converter {
+    resampler: FftFixedInOut<f32>,
+    channels: usize,
+    input: Vec<Vec<f32>>,
+    output: Vec<Vec<f32>>,
+}
+
+impl Converter {
+    fn new(from: u32, to: u32, channels: usize) -> Result<Self, ErrorKind> {
+        let chunk = from as usize * CHUNK_MS / 1000;
+        let resampler = FftFixedInOut::new(from as usize, to as usize, chunk, channels)
+            .map_err(|e| ErrorKind::InitializationError2(format!("resampler: {}", e)))?;
+        info!(
+            "Resampling {} channels from {} Hz to {} Hz",
+            channels, from, to
+        );
+        Ok(Converter {
+            input: resampler.input_buffer_allocate(true),
+            output: resampler.output_buffer_allocate(true),
+            resampler,
+            channels,
+        })
+    }
+
+    /// Input frames the next call to `process` takes
+    fn chunk(&self) -> usize {
+        self.resampler.input_frames_next()
+    }
+
+    /// Resamples exactly `chunk` interleaved frames and appends the result to `out`
+    fn process(
+        &mut self,
+        samples: impl Iterator<Item = f32>,
+        out: &mut VecDeque<f32>,
+    ) -> Result<(), String> {
+        let frames = self.chunk();
+        for channel in self.input.iter_mut() {
+            channel.resize(frames, 0.0);
+        }
+        for (i, s) in samples.take(frames * self.channels).enumerate() {
+            self.input[i % self.channels][i / self.channels] = s;
+        }
+        let (_, written) = self
+            .resampler
+            .process_into_buffer(&self.input, &mut self.output, None)
+            .map_err(|e| e.to_string())?;
+        for i in 0..written {
+            out.extend(self.output.iter().map(|channel| channel[i]));
+        }
+        Ok(())
+    }
+
+    /// Frames the resampler holds back, at the output rate
+    fn delay(&self) -> usize {
+        self.resampler.output_delay()
+    }
+}
+
+/// Records from a device running at another rate than the pipeline
+pub struct ResampledProducer {
+    inner: Box<dyn AudioProducer + Send>,
+    converter: Converter,
+    device_frame: AudioFrame,
+    // converted samples waiting for the next `produce`
+    queue: VecDeque<f32>,
+}
+
+impl ResampledProducer {
+    pub fn new(inner: Box<dyn AudioProducer + Send>, channels: usize) -> Result<Self, ErrorKind> {
+        let rate = inner.sample_rate();
+        let converter = Converter::new(rate, SAMPLE_RATE, channels)?;
+        Ok(ResampledProducer {
+            device_frame: AudioFrame::new(channels, rate, converter.chunk()),
+            inner,
+            converter,
+            queue: VecDeque::new(),
+        })
+    }
+}
+
+impl AudioProducer for ResampledProducer {
+    fn produce(&mut self, frame: &mut AudioFrame) -> Result<(), ErrorKind> {
+        let needed = frame.samples().len();
+        while self.queue.len() < needed {
+            self.inner.produce(&mut self.device_frame)?;
+            self.converter
+                .process(self.device_frame.samples().iter().copied(), &mut self.queue)
+                .map_err(|_| ErrorKind::ReadError)?;
+        }
+        for (out, s) in frame
+            .samples_mut()
+            .iter_mut()
+            .zip(self.queue.drain(..needed))
+        {
+            *out = s;
+        }
+        Ok(())
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        let frames = self.queue.len() / self.converter.channels + self.converter.delay();
+        let queued = Duration::from_micros(frames as u64 * 1_000_000 / SAMPLE_RATE as u64);
+        Some(self.inner.latency()? + queued)
+    }
+}
+
+/// Plays to a device running at another rate than the pipeline
+pub struct ResampledConsumer {
+    inner: Box<dyn Consumer + Send>,
+    converter: Converter,
+    rate: u32,
+    // pipeline samples waiting for a full resampler chunk
+    pending: VecDeque<f32>,
+    converted: VecDeque<f32>,
+}
+
+impl ResampledConsumer {
+    pub fn new(inner: Box<dyn Consumer + Send>, channels: usize) -> Result<Self, ErrorKind> {
+        let rate = inner.sample_rate();
+        Ok(ResampledConsumer {
+            converter: Converter::new(SAMPLE_RATE, rate, channels)?,
+            inner,
+            rate,
+            pending: VecDeque::new(),
+            converted: VecDeque::new(),
+        })
+    }
+}
+
+impl Consumer for ResampledConsumer {
+    fn consume(&mut self, frame: &AudioFrame) -> Result<usize, ErrorKind> {
+        let channels = self.converter.channels;
+        self.pending.extend(frame.samples());
+        while self.pending.len() >= self.converter.chunk() * channels {
+            let samples = self.converter.chunk() * channels;
+            self.converter
+                .process(self.pending.drain(..samples), &mut self.converted)
+                .map_err(ErrorKind::WriteError)?;
+        }
+        if !self.converted.is_empty() {
+            let device_frame =
+                AudioFrame::from_samples(channels, self.rate, self.converted.drain(..).collect());
+            self.inner.consume(&device_frame)?;
+        }
+        Ok(frame.frames())
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        let frames = self.pending.len() / self.converter.channels;
+        let queued = Duration::from_micros(frames as u64 * 1_000_000 / SAMPLE_RATE as u64)
+            + Duration::from_micros(self.converter.delay() as u64 * 1_000_000 / self.rate as u64);
+        Some(self.inner.latency()? + queued)
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI16, AtomicUsize, Ordering};
+
+/// Single producer single consumer sample queue that never blocks or allocates, so one
+/// side can live in a realtime audio callback
+struct Ring {
+    slots: Box<[AtomicI16]>,
+    // total samples ever written and read, the difference is the fill level
+    written: AtomicUsize,
+    read: AtomicUsize,
+}
+
+pub struct RingWriter {
+    ring: Arc<Ring>,
+}
+
+pub struct RingReader {
+    ring: Arc<Ring>,
+}
+
+pub fn ring(capacity: usize) -> (RingWriter, RingReader) {
+    let ring = Arc::new(Ring {
+        slots: (0..capacity).map(|_| AtomicI16::new(0)).collect(),
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+    });
+    (RingWriter { ring: ring.clone() }, RingReader { ring })
+}
+
+impl Ring {
+    fn len(&self) -> usize {
+        self.written
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+}
+
+impl RingWriter {
+    /// Room left for samples
+    pub fn free(&self) -> usize {
+        self.ring.slots.len() - self.ring.len()
+    }
+
+    /// Appends as many samples as fit, returns how many that were
+    pub fn push(&mut self, samples: &[i16]) -> usize {
+        let ring = &self.ring;
+        let count = samples.len().min(self.free());
+        let written = ring.written.load(Ordering::Relaxed);
+        for (i, &s) in samples[..count].iter().enumerate() {
+            ring.slots[written.wrapping_add(i) % ring.slots.len()].store(s, Ordering::Relaxed);
+        }
+        ring.written
+            .store(written.wrapping_add(count), Ordering::Release);
+        count
+    }
+}
+
+impl RingReader {
+    /// Samples waiting to be read
+    pub fn available(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// Fills `out` from the front of the queue, returns how many samples there were
+    pub fn pop(&mut self, out: &mut [i16]) -> usize {
+        let ring = &self.ring;
+        let count = out.len().min(self.available());
+        let read = ring.read.load(Ordering::Relaxed);
+        for (i, out) in out[..count].iter_mut().enumerate() {
+            *out = ring.slots[read.wrapping_add(i) % ring.slots.len()].load(Ordering::Relaxed);
+        }
+        ring.read.store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
+}
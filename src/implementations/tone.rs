@@ -0,0 +1,48 @@
+use std::f32::consts::PI;
+
+use super::Clock;
+use crate::{AudioFrame, AudioProducer, ErrorKind, SAMPLE_RATE};
+
+// -12 dBFS, loud enough for the voice detector without clipping after gain control
+const AMPLITUDE: f32 = 0.25;
+// the tone is switched on and off so voice detection and noise suppression don't
+// treat it as background noise
+const BEEP_FRAMES: u64 = SAMPLE_RATE as u64;
+
+/// Sine tone test source
+pub struct ToneProducer {
+    step: f32,
+    phase: f32,
+    position: u64,
+    clock: Clock,
+}
+
+impl ToneProducer {
+    pub fn new(frequency: f32) -> Self {
+        ToneProducer {
+            step: 2.0 * PI * frequency / SAMPLE_RATE as f32,
+            phase: 0.0,
+            position: 0,
+            clock: Clock::new(SAMPLE_RATE),
+        }
+    }
+}
+
+impl AudioProducer for ToneProducer {
+    fn produce(&mut self, frame: &mut AudioFrame) -> Result<(), ErrorKind> {
+        let channels = frame.channels();
+        for samples in frame.samples_mut().chunks_exact_mut(channels) {
+            let on = (self.position / BEEP_FRAMES).is_multiple_of(2);
+            let sample = if on {
+                self.phase.sin() * AMPLITUDE
+            } else {
+                0.0
+            };
+            samples.fill(sample);
+            self.phase = (self.phase + self.step) % (2.0 * PI);
+            self.position += 1;
+        }
+        self.clock.wait(frame.frames());
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::Clock;
+use crate::{AudioFrame, AudioProducer, CHANNELS, Consumer, ErrorKind, SAMPLE_RATE};
+
+const HEADER_LEN: u32 = 44;
+
+/// Plays a 16 bit PCM wav file as if it was recorded, over and over
+pub struct WavProducer {
+    samples: Vec<i16>,
+    file_channels: usize,
+    channels: usize,
+    // position in frames
+    position: usize,
+    rate: u32,
+    clock: Clock,
+}
+
+impl WavProducer {
+    pub fn open(path: &Path, channels: usize) -> Result<Self, ErrorKind> {
+        let error = |msg: &str| ErrorKind::ConfigError(format!("{}: {}", path.display(), msg));
+        let data = fs::read(path).map_err(|e| error(&e.to_string()))?;
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return Err(error("not a wav file"));
+        }
+
+        let mut format = None;
+        let mut samples = None;
+        let mut offset = 12;
+        while offset + 8 <= data.len() {
+            let id = &data[offset..offset + 4];
+            let len = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            let body = &data[offset + 8..(offset + 8 + len).min(data.len())];
+            match id {
+                b"fmt " if body.len() >= 16 => {
+                    let tag = u16::from_le_bytes([body[0], body[1]]);
+                    let channels = u16::from_le_bytes([body[2], body[3]]);
+                    let rate = u32::from_le_bytes(body[4..8].try_into().unwrap());
+                    let bits = u16::from_le_bytes([body[14], body[15]]);
+                    format = Some((tag, channels, rate, bits));
+                }
+                b"data" => {
+                    samples = Some(
+                        body.chunks_exact(2)
+                            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                            .collect::<Vec<i16>>(),
+                    );
+                }
+                _ => {}
+            }
+            // chunks are padded to an even length
+            offset += 8 + len + len % 2;
+        }
+
+        let Some((tag, file_channels, rate, bits)) = format else {
+            return Err(error("missing fmt chunk"));
+        };
+        if tag != 1 || bits != 16 || rate == 0 || !(1..=2).contains(&file_channels) {
+            return Err(error(&format!(
+                "need 16 bit PCM with 1 or 2 channels, got format {} with {} bit at {} Hz and {} channels",
+                tag, bits, rate, file_channels
+            )));
+        }
+        let samples = samples.unwrap_or_default();
+        if samples.len() < file_channels as usize {
+            return Err(error("no audio data"));
+        }
+        Ok(WavProducer {
+            samples,
+            file_channels: file_channels as usize,
+            channels,
+            position: 0,
+            rate,
+            clock: Clock::new(rate),
+        })
+    }
+}
+
+impl AudioProducer for WavProducer {
+    fn produce(&mut self, frame: &mut AudioFrame) -> Result<(), ErrorKind> {
+        let frames = frame.frames();
+        let file_frames = self.samples.len() / self.file_channels;
+        let mut out = Vec::with_capacity(frames * self.channels);
+        for _ in 0..frames {
+            let file_frame =
+                &self.samples[self.position * self.file_channels..][..self.file_channels];
+            match (self.file_channels, self.channels) {
+                (1, n) => out.extend(std::iter::repeat_n(file_frame[0], n)),
+                (2, 1) => out.push(((file_frame[0] as i32 + file_frame[1] as i32) / 2) as i16),
+                _ => out.extend_from_slice(&file_frame[..self.channels]),
+            }
+            self.position = (self.position + 1) % file_frames;
+        }
+        frame.fill_i16(out);
+        self.clock.wait(frames);
+        Ok(())
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+}
+
+/// Writes the played audio to a wav file. The header is kept up to date after every
+/// write so the file stays readable however the client exits.
+pub struct WavConsumer {
+    file: File,
+    data_len: u32,
+}
+
+impl WavConsumer {
+    pub fn create(path: &Path) -> Result<Self, ErrorKind> {
+        let file = File::create(path).map_err(|e| {
+            ErrorKind::InitializationError2(format!("Can't create {}: {}", path.display(), e))
+        })?;
+        let mut consumer = WavConsumer { file, data_len: 0 };
+        consumer
+            .write_header()
+            .map_err(|e| ErrorKind::InitializationError2(e.to_string()))?;
+        Ok(consumer)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let channels = CHANNELS as u16;
+        let block_align = channels * 2;
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_LEN - 8 + self.data_len).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&(SAMPLE_RATE * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&self.data_len.to_le_bytes());
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+impl Consumer for WavConsumer {
+    fn consume(&mut self, frame: &AudioFrame) -> Result<usize, ErrorKind> {
+        let bytes: Vec<u8> = frame.iter_i16().flat_map(i16::to_le_bytes).collect();
+        self.file
+            .write_all(&bytes)
+            .map_err(|e| ErrorKind::WriteError(e.to_string()))?;
+        self.data_len += bytes.len() as u32;
+        self.write_header()
+            .map_err(|e| ErrorKind::WriteError(e.to_string()))?;
+        Ok(frame.frames())
+    }
+}
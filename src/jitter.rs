@@ -1,6 +1,315 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use log::debug;
+
 use crate::server::AudioData;
+use crate::{FRAME_SIZE, SAMPLE_RATE};
+
+// keep one packet of lookahead so its FEC data can rebuild a lost predecessor
+const MIN_DEPTH: usize = 2;
+const MAX_DEPTH: usize = 12;
+// how many standard jitter deviations the target depth should cover
+const JITTER_FACTOR: f32 = 3.0;
+// a packet this far ahead of or behind the playout point means the sender restarted its
+// sequence
+const RESYNC_GAP: u64 = 50;
+
+/// What the playout side gets for a single frame slot
+#[derive(Debug)]
+pub enum Playout {
+    Packet(AudioData),
+    Missing(u32), // sequence number that never arrived
+    Empty,        // nothing buffered, still filling up to the target depth
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JitterStats {
+    pub received: u64,
+    pub late: u64,
+    pub duplicate: u64,
+    pub lost: u64,
+    pub dropped: u64, // discarded to shrink the buffer down to the target depth
+    pub underruns: u64,
+}
+
+/// Reorders incoming packets by sequence number and holds them back long enough to
+/// absorb the measured network jitter.
+pub struct JitterBuffer {
+    // keyed by the extended (wraparound-free) sequence number
+    packets: BTreeMap<u64, AudioData>,
+    next_seq: Option<u64>,
+    highest_seq: Option<u64>,
+    buffering: bool,
+    target_depth: usize,
+    frame_ms: f32,
+    // RFC 3550 interarrival jitter estimate in milliseconds
+    jitter_ms: f32,
+    // lower bound for it, from how much the round trip to the server varies
+    path_jitter_ms: f32,
+    last_transit: Option<f64>,
+    epoch: Instant,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new() -> Self {
+        JitterBuffer {
+            packets: BTreeMap::new(),
+            next_seq: None,
+            highest_seq: None,
+            buffering: true,
+            target_depth: 2,
+            frame_ms: FRAME_SIZE as f32 * 1000.0 / SAMPLE_RATE as f32,
+            jitter_ms: 0.0,
+            path_jitter_ms: 0.0,
+            last_transit: None,
+            epoch: Instant::now(),
+            stats: JitterStats::default(),
+        }
+    }
+
+    pub fn push(&mut self, packet: AudioData, arrival: Instant) {
+        self.stats.received += 1;
+        self.update_jitter(&packet, arrival);
+
+        let mut seq = self.extend_seq(packet.seq_number);
+        if let Some(next) = self.next_seq {
+            if seq + RESYNC_GAP < next || seq >= next + RESYNC_GAP {
+                debug!("Sequence jumped to {}, resyncing", packet.seq_number);
+                self.packets.clear();
+                self.next_seq = None;
+                self.highest_seq = None;
+                self.buffering = true;
+                seq = self.extend_seq(packet.seq_number);
+            } else if seq < next {
+                debug!(
+                    "Dropping late packet {} (playing {})",
+                    packet.seq_number, next
+                );
+                self.stats.late += 1;
+                return;
+            }
+        }
+        if self.packets.contains_key(&seq) {
+            self.stats.duplicate += 1;
+            return;
+        }
+        self.packets.insert(seq, packet);
+        if self.highest_seq.is_none_or(|highest| seq > highest) {
+            self.highest_seq = Some(seq);
+        }
+        // never hold more than the maximum depth, even if playout stalls
+        while self.packets.len() > MAX_DEPTH {
+            if let Some((seq, _)) = self.packets.pop_first() {
+                self.next_seq = Some(seq + 1);
+                self.stats.dropped += 1;
+            }
+        }
+    }
+
+    /// Returns the packet for the next frame slot, called once per playout tick
+    pub fn pop(&mut self) -> Playout {
+        if self.buffering {
+            if self.packets.len() < self.target_depth {
+                return Playout::Empty;
+            }
+            self.buffering = false;
+            // whatever went missing before the buffer refilled is gone for good
+            let first = *self.packets.keys().next().unwrap();
+            if let Some(next) = self.next_seq {
+                self.stats.lost += first.saturating_sub(next);
+            }
+            self.next_seq = Some(first);
+        }
+
+        // shrink slowly when the network calmed down and we are holding too much
+        if self.packets.len() > self.target_depth + 2
+            && let Some((seq, _)) = self.packets.pop_first()
+        {
+            self.next_seq = Some(seq + 1);
+            self.stats.dropped += 1;
+        }
+
+        let Some(next) = self.next_seq else {
+            return Playout::Empty;
+        };
+        match self.packets.first_key_value() {
+            Some((&seq, _)) if seq == next => {
+                self.next_seq = Some(next + 1);
+                Playout::Packet(self.packets.pop_first().unwrap().1)
+            }
+            Some(_) => {
+                self.next_seq = Some(next + 1);
+                self.stats.lost += 1;
+                Playout::Missing(next as u32)
+            }
+            None => {
+                self.buffering = true;
+                self.stats.underruns += 1;
+                Playout::Empty
+            }
+        }
+    }
+
+    /// Packet duration of the stream, the target depth is derived from it
+    pub fn set_frame_ms(&mut self, frame_ms: u8) {
+        self.frame_ms = frame_ms as f32;
+    }
+
+    /// How much the delay to the server varies, so a new stream starts out deep enough
+    /// before its own jitter is known
+    pub fn set_path_jitter(&mut self, jitter_ms: f32) {
+        self.path_jitter_ms = jitter_ms;
+        self.update_target_depth();
+    }
+
+    /// The packet that will be played next, if it already arrived
+    pub fn peek_next(&self) -> Option<&AudioData> {
+        self.packets.get(&self.next_seq?)
+    }
+
+    /// Forgets what arrived, packets sent meanwhile don't count as lost
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.next_seq = None;
+        self.highest_seq = None;
+        self.buffering = true;
+    }
+
+    pub fn target_depth(&self) -> usize {
+        self.target_depth
+    }
+
+    pub fn jitter_ms(&self) -> f32 {
+        self.jitter_ms
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+
+    /// Maps the wrapping 32 bit sequence number onto a monotonic 64 bit one
+    fn extend_seq(&self, seq: u32) -> u64 {
+        match self.highest_seq {
+            None => (1u64 << 32) + seq as u64,
+            Some(highest) => {
+                let delta = seq.wrapping_sub(highest as u32) as i32;
+                highest.wrapping_add_signed(delta as i64)
+            }
+        }
+    }
+
+    fn update_jitter(&mut self, packet: &AudioData, arrival: Instant) {
+        let arrival_ms = arrival.duration_since(self.epoch).as_secs_f64() * 1000.0;
+        let transit = arrival_ms - packet.timestamp as f64;
+        if let Some(last) = self.last_transit {
+            let d = (transit - last).abs() as f32;
+            self.jitter_ms += (d - self.jitter_ms) / 16.0;
+        }
+        self.last_transit = Some(transit);
+        self.update_target_depth();
+    }
+
+    fn update_target_depth(&mut self) {
+        let jitter_ms = self.jitter_ms.max(self.path_jitter_ms);
+        let depth = (JITTER_FACTOR * jitter_ms / self.frame_ms).ceil() as usize + 1;
+        self.target_depth = depth.clamp(MIN_DEPTH, MAX_DEPTH);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // evenly spaced packets, so the jitter stays zero and the target depth at its minimum
+    fn push(buffer: &mut JitterBuffer, seq: u32, tick: u64) {
+        let packet = AudioData {
+            stream_id: 0,
+            timestamp: tick * 20,
+            seq_number: seq,
+            channels: 1,
+            frame_ms: 20,
+            data: vec![],
+        };
+        let arrival = buffer.epoch + Duration::from_millis(tick * 20);
+        buffer.push(packet, arrival);
+    }
+
+    /// Sequence numbers of everything playable right now
+    fn play(buffer: &mut JitterBuffer) -> Vec<u32> {
+        let mut played = Vec::new();
+        loop {
+            match buffer.pop() {
+                Playout::Packet(packet) => played.push(packet.seq_number),
+                Playout::Missing(seq) => panic!("{} went missing", seq),
+                Playout::Empty => return played,
+            }
+        }
+    }
+
+    #[test]
+    fn reorders() {
+        let mut buffer = JitterBuffer::new();
+        for (tick, seq) in [1, 3, 2, 4].into_iter().enumerate() {
+            push(&mut buffer, seq, tick as u64);
+        }
+        assert_eq!(play(&mut buffer), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let mut buffer = JitterBuffer::new();
+        for (tick, seq) in [1, 2, 2, 3].into_iter().enumerate() {
+            push(&mut buffer, seq, tick as u64);
+        }
+        assert_eq!(play(&mut buffer), [1, 2, 3]);
+        assert_eq!(buffer.stats().duplicate, 1);
+    }
+
+    #[test]
+    fn follows_the_sequence_across_the_wrap() {
+        let mut buffer = JitterBuffer::new();
+        let seqs = [u32::MAX - 1, 0, u32::MAX, 1];
+        for (tick, seq) in seqs.into_iter().enumerate() {
+            push(&mut buffer, seq, tick as u64);
+        }
+        assert_eq!(play(&mut buffer), [u32::MAX - 1, u32::MAX, 0, 1]);
+        push(&mut buffer, 2, 4);
+        push(&mut buffer, 3, 5);
+        assert_eq!(play(&mut buffer), [2, 3]);
+        assert_eq!(buffer.stats().late, 0);
+    }
+
+    #[test]
+    fn clearing_loses_nothing() {
+        let mut buffer = JitterBuffer::new();
+        for tick in 0..4 {
+            push(&mut buffer, tick as u32, tick);
+        }
+        assert_eq!(play(&mut buffer), [0, 1, 2, 3]);
+        // deafened for a moment
+        buffer.clear();
+        for tick in 20..24 {
+            push(&mut buffer, tick as u32, tick);
+        }
+        assert_eq!(play(&mut buffer), [20, 21, 22, 23]);
+        assert_eq!(buffer.stats().lost, 0);
+    }
 
-struct JitterBuffer {
-    buffer: Vec<AudioData>,
-    max_size: usize,
+    #[test]
+    fn resyncs_when_the_sender_restarts() {
+        let mut buffer = JitterBuffer::new();
+        for tick in 0..4 {
+            push(&mut buffer, 1000 + tick as u32, tick);
+        }
+        assert_eq!(play(&mut buffer), [1000, 1001, 1002, 1003]);
+        for tick in 4..8 {
+            push(&mut buffer, tick as u32 - 3, tick);
+        }
+        assert_eq!(play(&mut buffer), [1, 2, 3, 4]);
+        assert_eq!(buffer.stats().late, 0);
+    }
 }
@@ -1,7 +1,9 @@
 use std::fs::File;
 use std::io::Write;
+use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, mpsc};
+use std::time::Duration;
 
 use libpulse_binding as pulse;
 use libpulse_simple_binding as psimple;
@@ -9,20 +11,31 @@ use log::{LevelFilter, info};
 use tokio::net::UdpSocket;
 use tokio::signal;
 
-use crate::audio::{play_audio, record_audio};
+use crate::audio::{CaptureSettings, TransmitMode, play_audio, record_audio, share_audio};
 use crate::client::NetworkClient;
-use crate::coordinator::run_coordinator;
-use crate::implementations::pulseaudio::{PulseAudioConsumer, PulseAudioProducer};
+use crate::config::Config;
+use crate::coordinator::{AudioDevices, run_coordinator};
+use crate::frame::AudioFrame;
+use crate::implementations::{BackendSettings, open_consumer, open_producer, open_share};
 use crate::mp3player::decode_mp3;
 
+mod aec;
+mod agc;
 mod audio;
 mod client;
+mod codec;
+mod config;
 mod coordinator;
+mod denoise;
+mod frame;
 mod implementations;
 mod server;
 mod tui;
 mod mp3player;
 mod jitter;
+mod mixer;
+mod rtt;
+mod vad;
 
 const SAMPLE_RATE: u32 = 48000;
 const CHANNELS: usize = 2;
@@ -31,28 +44,66 @@ const MSG_SIZE: u32 = BUF_SIZE + 1;
 const FRAME_SIZE: usize = 960; // for opus - 20ms at 48kHz. Per channel, so total samples = FRAME_SIZE * CHANNELS = 1920
 
 #[derive(Debug)]
-enum ErrorKind {
+pub enum ErrorKind {
     InitializationError,
     InitializationError2(String),
     WriteError(String),
     ReadError,
+    EncodeError(String),
+    ConfigError(String),
 }
 
 #[derive(Debug, Default)]
 pub struct ClientState {
     sending_audio: bool,
     connected: bool,
+    // the one we're connected to, or trying next
+    server: Option<String>,
+    // what the server last told us went wrong
+    server_error: Option<String>,
+    rtt: Option<rtt::RttStats>,
+    // how long audio takes through the sound cards, see `ClientMessage::DeviceLatency`
+    input_latency: Option<Duration>,
+    output_latency: Option<Duration>,
     mute: bool,
     deafen: bool,
+    ptt_pressed: bool,
+    noise_suppression: bool,
+    input_gain_db: f32,
+    // last thing that happened to the audio devices
+    device_status: Option<String>,
+    input_lost: bool,
+    output_lost: bool,
+    // we share application audio, `share_paused` holds it back for a while
+    share: bool,
+    share_paused: bool,
     exit: bool,
 }
 
-trait AudioProducer {
-    fn produce(&mut self, data: &mut [u8]) -> Result<(), ErrorKind>;
+pub trait AudioProducer {
+    /// Records as many samples as `frame` holds, in the channel layout the producer was opened with
+    fn produce(&mut self, frame: &mut AudioFrame) -> Result<(), ErrorKind>;
+    /// How long ago the audio returned by `produce` hit the microphone, if the backend knows
+    fn latency(&self) -> Option<Duration> {
+        None
+    }
+    /// Rate the device records at, anything but `SAMPLE_RATE` gets resampled
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
 }
 
-trait Consumer {
-    fn consume(&mut self, data: &[u8]) -> Result<usize, ErrorKind>;
+pub trait Consumer {
+    /// Plays the whole frame, returns how many samples per channel that were
+    fn consume(&mut self, frame: &AudioFrame) -> Result<usize, ErrorKind>;
+    /// How long until audio passed to `consume` reaches the speakers, if the backend knows
+    fn latency(&self) -> Option<Duration> {
+        None
+    }
+    /// Rate the device plays at, anything but `SAMPLE_RATE` gets resampled
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
 }
 
 //mod external;
@@ -62,10 +113,13 @@ fn main() {
         let mut server = false;
         let mut client = true;
         let mut test_audio = false;
+        let mut list_devices = false;
         let mut tui = true;
         let mut debug = false;
-        let mut ip = "kopatz.dev:1234".to_string();
-        let mut args = std::env::args().skip(1).peekable();
+        let mut servers_from_args = false;
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let mut config = load_config(&args);
+        let mut args = args.into_iter().peekable();
         let (tx_msg, rx_msg): (
             Sender<client::ClientMessage>,
             Receiver<client::ClientMessage>,
@@ -98,17 +152,45 @@ fn main() {
                     server = true;
                     client = false;
                 }
+                "--list-devices" => {
+                    list_devices = true;
+                    client = false;
+                }
                 "--no-tui" => {
                     tui = false;
                 }
                 "--ip" => {
-                    if let Some(val) = args.next() {
-                        ip = val;
-                    } else {
-                        eprintln!("--ip requires an address argument");
-                        std::process::exit(1);
+                    // the first one replaces the servers of the config file
+                    if !servers_from_args {
+                        config.servers.clear();
+                        servers_from_args = true;
                     }
+                    set_option(&mut config, "ip", args.next());
+                }
+                "--config" => {
+                    // already loaded before parsing the other arguments
+                    args.next();
                 }
+                "--backend" | "--input-device" | "--output-device" | "--tone-frequency" | "--wav-input" | "--wav-output"
+                | "--share" | "--share-bitrate"
+                | "--alsa-device" | "--alsa-period" | "--alsa-buffer"
+                | "--pipewire-node-name" | "--pipewire-quantum" | "--jack-client-name"
+                | "--bitrate" | "--complexity" | "--channels" | "--application" | "--frame-ms"
+                | "--vad-threshold" | "--vad-attack-ms" | "--vad-hangover-ms"
+                | "--transmit-mode" | "--ptt-tail-ms" | "--noise-suppression-strength"
+                | "--agc-target" | "--agc-max-gain" | "--input-gain"
+                | "--heartbeat-interval-ms" | "--heartbeat-timeout-ms" => {
+                    let key = arg.trim_start_matches("--").replace('-', "_");
+                    set_option(&mut config, &key, args.next());
+                }
+                "--cbr" => config.codec.vbr = false,
+                "--no-calibrate" => config.vad.calibrate = false,
+                "--ptt" => config.transmit_mode = TransmitMode::PushToTalk,
+                "--no-noise-suppression" => config.noise_suppression.enabled = false,
+                "--no-echo-cancellation" => config.echo_cancellation = false,
+                "--no-agc" => config.gain.automatic = false,
+                "--no-jack-autoconnect" => config.backend.jack_autoconnect = false,
+                "--no-plc" => config.playback.plc = false,
                 "--debug" => debug = true,
                 "--help" => help(),
                 "--h" => help(),
@@ -126,6 +208,13 @@ fn main() {
         if !client && tui {
             tui = false;
         }
+        if let Err(e) = config.backend.validate() {
+            exit_with_error(e);
+        }
+        if client && !tui && config.transmit_mode == TransmitMode::PushToTalk {
+            eprintln!("Push to talk needs the terminal user interface to read the key");
+            std::process::exit(1);
+        }
         if !tui {
             env_logger::Builder::from_env(env_logger::Env::default().filter_or("RUST_LOG", "info"))
                 .init();
@@ -155,23 +244,73 @@ fn main() {
         }
         if client {
             //todo: some way to mute and deafen
-            let mut audio_consumer = PulseAudioConsumer::new().unwrap();
-            let mut audio_producer = PulseAudioProducer::new().unwrap();
+            let audio_consumer =
+                open_consumer(&config.backend).unwrap_or_else(|e| exit_with_error(e));
+            let frame_bytes = config.codec.frame_size() * config.codec.channels * 2;
+            let audio_producer =
+                open_producer(&config.backend, config.codec.channels, frame_bytes as u32)
+                    .unwrap_or_else(|e| exit_with_error(e));
+            let capture_settings = CaptureSettings {
+                codec: config.codec.clone(),
+                vad: config.vad.clone(),
+                noise_suppression: config.noise_suppression.clone(),
+                echo_cancellation: config.echo_cancellation,
+                gain: config.gain.clone(),
+                mode: config.transmit_mode,
+                ptt_tail: config.ptt_tail,
+            };
+            // what we play goes to the echo canceller of the capture side
+            let (tx_echo, rx_echo) = mpsc::channel::<AudioFrame>();
             let tx_msg_clone = tx_msg.clone();
-            tokio::spawn(async move { record_audio(tx_msg_clone, &mut audio_producer, rx_record) });
-            tokio::spawn(async move { play_audio(rx_playback, &mut audio_consumer) });
-            let network_client = NetworkClient::new(&ip, tx_msg.clone()).await.unwrap();
-            network_client.start(rx_net_in, rx_net_out).await;
+            tokio::spawn(async move { record_audio(tx_msg_clone, audio_producer, rx_record, rx_echo, capture_settings) });
+            let tx_msg_clone = tx_msg.clone();
+            let playback_settings = config.playback.clone();
+            tokio::spawn(async move {
+                play_audio(
+                    tx_msg_clone,
+                    rx_playback,
+                    audio_consumer,
+                    tx_echo,
+                    playback_settings,
+                )
+            });
+            let mut tx_share = None;
+            if config.backend.share_source.is_some() {
+                let profile = config.share_codec.clone();
+                let frame_bytes = profile.frame_size() * profile.channels * 2;
+                let share_producer =
+                    open_share(&config.backend, profile.channels, frame_bytes as u32)
+                        .unwrap_or_else(|e| exit_with_error(e));
+                let (tx, rx_share) = mpsc::channel();
+                tx_share = Some(tx);
+                let tx_msg_clone = tx_msg.clone();
+                // a thread of its own, the runtime's workers are busy with the other loops
+                std::thread::spawn(move || {
+                    share_audio(tx_msg_clone, share_producer, rx_share, profile)
+                });
+            }
+            let network_client = NetworkClient::new(config.servers(), tx_msg.clone());
+            network_client.start(rx_net_in, rx_net_out, config.heartbeat).await;
+            // the coordinator opens the devices again when they are switched or lost
+            let devices =
+                AudioDevices::new(config.backend, config.codec.channels, frame_bytes as u32);
             if tui {
-                tokio::spawn(async move { tui::App::new(rx_tui, tx_msg) });
+                let ptt = config.transmit_mode == TransmitMode::PushToTalk;
+                let noise_suppression = config.noise_suppression.enabled;
+                let share = tx_share.is_some();
+                tokio::spawn(async move {
+                    tui::App::new(rx_tui, tx_msg, ptt, noise_suppression, share)
+                });
             }
             run_coordinator(
                 rx_msg,
                 tx_playback.clone(),
                 tx_record.clone(),
+                tx_share,
                 tx_tui.clone(),
                 tx_net_out.clone(),
                 tx_net_in.clone(),
+                devices,
             )
             .await;
             // TODO: wait for ctrl-c in non-tui mode, send Bye to server
@@ -189,10 +328,13 @@ fn main() {
             let listener = UdpSocket::bind("0.0.0.0:1234").await.unwrap();
             info!("Listening on 0.0.0.0:1234");
             //receive_audio(Arc::new(listener)).await;
-            server::server_loop(listener).await;
+            server::server_loop(listener, config.heartbeat).await;
+        } else if list_devices {
+            print_devices(&config.backend);
         } else if test_audio {
             println!("Playing test audio from seashore.mp3");
-            let mut audio_consumer = PulseAudioConsumer::new().unwrap();
+            let mut audio_consumer =
+                open_consumer(&config.backend).unwrap_or_else(|e| exit_with_error(e));
             let data = decode_mp3("seashore.mp3");
             println!("Decoded {} samples", data.len());
             let data = mp3player::resample_to_48k(&data, 44100);
@@ -215,13 +357,111 @@ fn main() {
     })
 }
 
+/// Reads the file given with --config, or the default config file if it exists
+fn load_config(args: &[String]) -> Config {
+    let path = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => match args.get(i + 1) {
+            Some(path) => PathBuf::from(path),
+            None => {
+                eprintln!("--config requires a path argument");
+                std::process::exit(1);
+            }
+        },
+        None => match config::default_path() {
+            Some(path) if path.exists() => path,
+            _ => return Config::default(),
+        },
+    };
+    Config::load(&path).unwrap_or_else(|e| exit_with_error(e))
+}
+
+fn set_option(config: &mut Config, key: &str, value: Option<String>) {
+    let Some(value) = value else {
+        eprintln!("--{} requires a value", key.replace('_', "-"));
+        std::process::exit(1);
+    };
+    if let Err(e) = config.set(key, &value) {
+        exit_with_error(e);
+    }
+}
+
+fn print_devices(settings: &BackendSettings) {
+    let devices = implementations::list_devices(settings).unwrap_or_else(|e| exit_with_error(e));
+    let sections = [
+        ("Input devices:", &devices.sources),
+        ("Monitors of output devices:", &devices.monitors),
+        ("Output devices:", &devices.sinks),
+    ];
+    for (title, list) in sections {
+        if list.is_empty() {
+            continue;
+        }
+        println!("{}", title);
+        for device in list {
+            let marker = if device.is_default { '*' } else { ' ' };
+            println!(
+                "{} {}\n      {}, {} Hz",
+                marker, device.name, device.description, device.rate
+            );
+        }
+    }
+    println!("* marks the default device");
+}
+
+fn exit_with_error(e: ErrorKind) -> ! {
+    match e {
+        ErrorKind::ConfigError(msg) => eprintln!("{}", msg),
+        e => eprintln!("{:?}", e),
+    }
+    std::process::exit(1);
+}
+
 fn help() {
     println!(
-        "Usage: {} [--server|--client] [--ip <address:port>] [--no-tui]",
+        "Usage: {} [--server|--client|--list-devices] [--ip <address:port>] [--config <path>] [--no-tui] [--no-plc]",
         std::env::args().next().unwrap()
     );
+    println!(
+        "       [--bitrate <bps|auto>] [--complexity <0-10>] [--cbr] [--channels <mono|stereo>]"
+    );
+    println!("       [--application <voip|audio|lowdelay>] [--frame-ms <10|20|40|60>]");
+    println!(
+        "       [--vad-threshold <dB>] [--vad-attack-ms <ms>] [--vad-hangover-ms <ms>] [--no-calibrate]"
+    );
+    println!("       [--backend <pulse|alsa|pipewire|jack|null|tone|wav>] [--tone-frequency <Hz>] [--wav-input <path>] [--wav-output <path>]");
+    println!("       [--input-device <name>] [--output-device <name>]");
+    println!("       [--share <source|virtual>] [--share-bitrate <bps>]");
+    println!("       [--alsa-device <name>] [--alsa-period <frames>] [--alsa-buffer <frames>]");
+    println!("       [--pipewire-node-name <name>] [--pipewire-quantum <frames>]");
+    println!("       [--jack-client-name <name>] [--no-jack-autoconnect]");
+    println!("       [--agc-target <dBFS>] [--agc-max-gain <dB>] [--no-agc] [--input-gain <dB>]");
+    println!("       [--transmit-mode <vad|ptt>] [--ptt] [--ptt-tail-ms <ms>]");
+    println!("       [--no-noise-suppression] [--noise-suppression-strength <0-1>] [--no-echo-cancellation]");
+    println!("       [--heartbeat-interval-ms <ms>] [--heartbeat-timeout-ms <ms>]");
     println!("If neither --server nor --client is specified, defaults to --client.");
-    println!("--ip specifies the IP address and port to connect to.");
+    println!("--ip specifies the IP address and port to connect to, repeat it for fallback servers");
+    println!("  that are tried in order when the one before can't be reached.");
     println!("--no-tui disables the terminal user interface.");
+    println!("--no-plc plays silence for lost packets instead of concealing them.");
+    println!("--config reads settings from a file instead of ~/.config/kop-audio/config.");
+    println!("--list-devices prints the pulseaudio sources and sinks, --input-device and --output-device");
+    println!("  take their names to use something other than the default device.");
+    println!("--share sends application audio next to the voice, from a source like the monitor of a");
+    println!("  sink, or from a sink called kop-audio-share we create for the call with virtual.");
+    println!("--backend picks where audio is recorded from and played to. null, tone and wav need no");
+    println!("  sound card, wav reads 16 bit --wav-input in a loop and writes to --wav-output.");
+    println!("  alsa, pipewire and jack need a build with the feature of the same name.");
+    println!("  --alsa-period, --alsa-buffer and --pipewire-quantum are in frames at 48kHz.");
+    println!("--bitrate, --complexity, --cbr, --channels, --application and --frame-ms");
+    println!("  set up the opus encoder for the audio we send.");
+    println!("--vad-threshold is how many dB above the room noise counts as speech.");
+    println!("--no-calibrate skips measuring the room noise at startup.");
+    println!("--no-noise-suppression sends the microphone signal without removing background noise.");
+    println!("--no-echo-cancellation stops removing the speaker output from the microphone, e.g. with headphones.");
+    println!("--agc-target is the speech loudness the automatic gain aims for, --no-agc uses");
+    println!("  the fixed --input-gain instead.");
+    println!("--ptt only sends audio while <Space> is held, --ptt-tail-ms keeps sending after release.");
+    println!("--heartbeat-interval-ms is how often client and server tell each other they are still there,");
+    println!("  after --heartbeat-timeout-ms without a word the other side counts as gone.");
     std::process::exit(0);
 }
@@ -0,0 +1,55 @@
+use crate::{AudioFrame, SAMPLE_RATE};
+
+// level above which the mix gets compressed instead of hard clipped
+const KNEE: f32 = 0.8;
+
+/// Sums decoded frames of all remote speakers into a single output frame
+pub struct Mixer {
+    mix: AudioFrame,
+    sources: usize,
+}
+
+impl Mixer {
+    pub fn new(channels: usize, frames: usize) -> Self {
+        Mixer {
+            mix: AudioFrame::new(channels, SAMPLE_RATE, frames),
+            sources: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.mix.silence();
+        self.sources = 0;
+    }
+
+    /// Adds a frame to the mix, shorter frames are treated as padded with silence
+    pub fn add(&mut self, frame: &AudioFrame) {
+        for (acc, &s) in self.mix.samples_mut().iter_mut().zip(frame.samples()) {
+            *acc += s;
+        }
+        self.sources += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources == 0
+    }
+
+    /// Finishes the mix, call `clear` before adding the next round of frames
+    pub fn mix(&mut self) -> &AudioFrame {
+        for s in self.mix.samples_mut() {
+            *s = soft_clip(*s);
+        }
+        &self.mix
+    }
+}
+
+/// Passes samples below the knee unchanged and smoothly saturates everything above towards full scale
+fn soft_clip(x: f32) -> f32 {
+    let magnitude = x.abs();
+    if magnitude <= KNEE {
+        return x;
+    }
+    let range = 1.0 - KNEE;
+    let compressed = KNEE + range * ((magnitude - KNEE) / range).tanh();
+    compressed.copysign(x)
+}
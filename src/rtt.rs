@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+// pings older than this many are given up on
+const MAX_OUTSTANDING: usize = 8;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RttStats {
+    pub rtt_ms: f32,      // smoothed round trip time
+    pub variance_ms: f32, // mean deviation of the round trip time
+    pub sent: u64,
+    pub lost: u64,
+}
+
+/// Pings the server and keeps a smoothed round trip time and its variation the way TCP
+/// does (RFC 6298)
+pub struct RttEstimator {
+    epoch: Instant,
+    next_nonce: u32,
+    // pings without a pong yet, oldest first
+    outstanding: VecDeque<u32>,
+    next_ping: Instant,
+    measured: bool,
+    stats: RttStats,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        RttEstimator {
+            epoch: now,
+            next_nonce: 0,
+            outstanding: VecDeque::with_capacity(MAX_OUTSTANDING),
+            next_ping: now,
+            measured: false,
+            stats: RttStats::default(),
+        }
+    }
+
+    /// When the next ping is due
+    pub fn next_ping(&self) -> Instant {
+        self.next_ping
+    }
+
+    /// Nonce and timestamp of a new ping
+    pub fn ping(&mut self, now: Instant) -> (u32, u64) {
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        if self.outstanding.len() == MAX_OUTSTANDING {
+            self.outstanding.pop_front();
+            self.stats.lost += 1;
+        }
+        self.outstanding.push_back(nonce);
+        self.stats.sent += 1;
+        self.next_ping = now + PING_INTERVAL;
+        (nonce, now.duration_since(self.epoch).as_micros() as u64)
+    }
+
+    /// Takes the answer to a ping and returns the round trip time it measured, stale and
+    /// duplicate pongs are ignored
+    pub fn pong(&mut self, nonce: u32, timestamp: u64, now: Instant) -> Option<Duration> {
+        let index = self.outstanding.iter().position(|&sent| sent == nonce)?;
+        // the pings before this one won't be answered anymore
+        self.stats.lost += index as u64;
+        self.outstanding.drain(..=index);
+        let rtt = now.checked_duration_since(self.epoch + Duration::from_micros(timestamp))?;
+
+        let rtt_ms = rtt.as_secs_f32() * 1000.0;
+        if self.measured {
+            let deviation = (self.stats.rtt_ms - rtt_ms).abs();
+            self.stats.variance_ms += (deviation - self.stats.variance_ms) / 4.0;
+            self.stats.rtt_ms += (rtt_ms - self.stats.rtt_ms) / 8.0;
+        } else {
+            self.stats.rtt_ms = rtt_ms;
+            self.stats.variance_ms = rtt_ms / 2.0;
+            self.measured = true;
+        }
+        debug!(
+            "Ping {} took {:.1} ms, smoothed {:.1} ms +- {:.1}",
+            nonce, rtt_ms, self.stats.rtt_ms, self.stats.variance_ms
+        );
+        Some(rtt)
+    }
+
+    pub fn stats(&self) -> RttStats {
+        self.stats
+    }
+}
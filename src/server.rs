@@ -1,40 +1,283 @@
-use std::net::SocketAddr;
+use std::fmt;
+use std::time::{Duration, Instant};
 
-use crate::BUF_SIZE;
+use crate::{BUF_SIZE, ErrorKind};
 use bincode::{Decode, Encode, config};
 use log::{debug, error, info, warn};
 use tokio::net::UdpSocket;
 
+/// Every packet starts with these, anything else on the port is dropped
+pub const MAGIC: [u8; 2] = *b"KA";
+/// Bumped whenever the payload of a message changes
+pub const PROTOCOL_VERSION: u8 = 2;
+/// We still speak the version before the current one, so servers and clients can be
+/// updated one after the other
+pub const OLDEST_VERSION: u8 = if PROTOCOL_VERSION > 1 {
+    PROTOCOL_VERSION - 1
+} else {
+    1
+};
+/// First version whose `Ping` carries a nonce and gets a `Pong`, and that sends `Heartbeat`s
+pub const PING_VERSION: u8 = 2;
+// clients from before heartbeats only show up when they talk
+const LEGACY_TIMEOUT: Duration = Duration::from_secs(500);
+// magic, version, message type and flags, no flags are defined yet
+const HEADER_LEN: usize = 5;
+// answers to addresses we don't know per heartbeat interval, see `server_loop`
+const MAX_STRANGER_REPLIES: usize = 16;
+
+/// How often both sides send a `Heartbeat`, and how long they wait to hear anything from
+/// the other before giving up on it
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatSettings {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        HeartbeatSettings {
+            interval: Duration::from_secs(2),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl HeartbeatSettings {
+    pub fn set_interval(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.interval = parse_ms(value, "heartbeat interval", 100..=60_000)?;
+        Ok(())
+    }
+
+    /// Should be a few intervals, a single lost heartbeat isn't a dead peer
+    pub fn set_timeout(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.timeout = parse_ms(value, "heartbeat timeout", 1000..=600_000)?;
+        Ok(())
+    }
+}
+
+fn parse_ms(
+    value: &str,
+    name: &str,
+    range: std::ops::RangeInclusive<u64>,
+) -> Result<Duration, ErrorKind> {
+    match value.parse::<u64>() {
+        Ok(ms) if range.contains(&ms) => Ok(Duration::from_millis(ms)),
+        _ => Err(ErrorKind::ConfigError(format!(
+            "{} must be between {} and {} ms, got {}",
+            name,
+            range.start(),
+            range.end(),
+            value
+        ))),
+    }
+}
+
+/// Features a peer has on top of plain voice forwarding, exchanged in `Hello`
+pub const CAP_SHARE_STREAM: u32 = 1 << 0; // mixes a `SHARE_STREAM` next to the voice
+pub const CAP_RECEIVER_REPORTS: u32 = 1 << 1; // sends and adapts to `ReceiverReport`s
+/// What this build supports
+pub const CAPABILITIES: u32 = CAP_SHARE_STREAM | CAP_RECEIVER_REPORTS;
+
+/// Codes of `Message::Error`, a peer that doesn't know a code still shows the reason
+pub const ERROR_MALFORMED: u16 = 1; // a packet couldn't be parsed
+pub const ERROR_NOT_CONNECTED: u16 = 2; // sent something without an accepted `Hello`
+pub const ERROR_UNSUPPORTED_VERSION: u16 = 3; // a packet used a version outside the agreed range
+
+/// The microphone, each client sends at most one of these
+pub const VOICE_STREAM: u8 = 0;
+/// Application audio a client shares next to its voice
+pub const SHARE_STREAM: u8 = 1;
+
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub struct AudioData {
+    /// Which of the sender's streams this belongs to, receivers decode and mix each one
+    /// separately
+    pub stream_id: u8,
     pub timestamp: u64,
     pub seq_number: u32,
+    pub channels: u8,
+    pub frame_ms: u8,
     pub data: Vec<u8>,
 }
 
-#[derive(Encode, Decode, PartialEq, Debug)]
+/// First message of a client, the server answers with `Welcome` or `Rejected`. These three
+/// and `Error` keep their layout in every version so even peers that can't talk otherwise
+/// understand why.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
+pub struct Hello {
+    /// Range of protocol versions the client speaks
+    pub min_version: u8,
+    pub max_version: u8,
+    pub capabilities: u32,
+}
+
+impl Hello {
+    /// What this build offers
+    pub fn ours() -> Self {
+        Hello {
+            min_version: OLDEST_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+        }
+    }
+
+    /// The newest version both sides speak, or why there is none
+    pub fn negotiate(&self) -> Result<u8, String> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+        if version < self.min_version.max(OLDEST_VERSION) {
+            return Err(format!(
+                "incompatible protocol, the server speaks versions {} to {} and the client {} to {}",
+                OLDEST_VERSION, PROTOCOL_VERSION, self.min_version, self.max_version
+            ));
+        }
+        Ok(version)
+    }
+}
+
+/// Sent by a receiver about a single remote sender, so it can tune its error correction
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
+pub struct ReceiverReport {
+    pub source: std::net::SocketAddr, // the sender this report is about
+    pub stream_id: u8,
+    pub loss_percent: u8,
+}
+
+#[derive(PartialEq, Debug)]
 pub enum Message {
     Audio(AudioData), // decoded audio packet
     AudioFrom(std::net::SocketAddr, AudioData),
-    Ping,
-    Hello(std::net::SocketAddr), // maybe UTF-8
+    /// Sent by the client every now and then, the server echoes both fields in a `Pong`
+    Ping {
+        nonce: u32,
+        timestamp: u64, // microseconds on the client's clock
+    },
+    Pong {
+        nonce: u32,
+        timestamp: u64,
+    },
+    Hello(Hello),
+    /// The server accepted a `Hello`, with the version to use from now on and the
+    /// capabilities both sides have
+    Welcome {
+        version: u8,
+        capabilities: u32,
+    },
+    /// The server turned a `Hello` down, the reason is meant for the user
+    Rejected(String),
     NewClient(std::net::SocketAddr),
     DeleteClient(std::net::SocketAddr),
     Bye,
-    Unknown(Vec<u8>),
+    Report(ReceiverReport),
+    ReportFrom(std::net::SocketAddr, ReceiverReport),
+    /// Something the peer sent went wrong, the connection stays up
+    Error {
+        code: u16,
+        reason: String,
+    },
+    /// The server dropped the client, it has to say hello again to come back
+    Kicked {
+        reason: String,
+    },
+    /// Sent by both sides every `HeartbeatSettings::interval`, so silence means the other
+    /// one is gone
+    Heartbeat,
 }
 
+/// Why a packet isn't a message
+#[derive(Debug)]
+pub enum DecodeError {
+    TooShort(usize),
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    Payload(u8, bincode::error::DecodeError),
+}
+
+impl DecodeError {
+    /// The `Message::Error` code to answer with
+    pub fn code(&self) -> u16 {
+        match self {
+            DecodeError::UnsupportedVersion(_) => ERROR_UNSUPPORTED_VERSION,
+            _ => ERROR_MALFORMED,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::TooShort(len) => write!(f, "packet of {} bytes is too short", len),
+            DecodeError::BadMagic => write!(f, "not a kop-audio packet"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "protocol version {} is not supported", version)
+            }
+            DecodeError::UnknownType(message_type) => {
+                write!(f, "unknown message type {}", message_type)
+            }
+            DecodeError::Payload(message_type, e) => {
+                write!(f, "bad payload in message type {}: {}", message_type, e)
+            }
+        }
+    }
+}
+
+// message types in the header
+const TYPE_AUDIO: u8 = 1;
+const TYPE_AUDIO_FROM: u8 = 2;
+const TYPE_PING: u8 = 3;
+const TYPE_HELLO: u8 = 4;
+const TYPE_WELCOME: u8 = 5;
+const TYPE_REJECTED: u8 = 6;
+const TYPE_NEW_CLIENT: u8 = 7;
+const TYPE_DELETE_CLIENT: u8 = 8;
+const TYPE_BYE: u8 = 9;
+const TYPE_REPORT: u8 = 10;
+const TYPE_REPORT_FROM: u8 = 11;
+const TYPE_ERROR: u8 = 12;
+const TYPE_KICKED: u8 = 13;
+const TYPE_PONG: u8 = 14;
+const TYPE_HEARTBEAT: u8 = 15;
+
 struct ClientInfo {
     addr: std::net::SocketAddr,
-    last_active: std::time::Instant,
+    last_active: Instant,
+    // agreed on in the handshake, everything sent to the client uses them
+    version: u8,
+    capabilities: u32,
+}
+
+impl ClientInfo {
+    /// Whether the client wants `msg` forwarded at all
+    fn wants(&self, msg: &Message) -> bool {
+        match msg {
+            Message::AudioFrom(_, audio) if audio.stream_id == SHARE_STREAM => {
+                self.capabilities & CAP_SHARE_STREAM != 0
+            }
+            Message::ReportFrom(..) => self.capabilities & CAP_RECEIVER_REPORTS != 0,
+            _ => true,
+        }
+    }
 }
 
-pub async fn server_loop(socket: UdpSocket) {
+pub async fn server_loop(socket: UdpSocket, heartbeat: HeartbeatSettings) {
     let mut buf = [0u8; BUF_SIZE as usize];
     let mut clients: Vec<ClientInfo> = Vec::new();
-    let mut check_counter = 0;
+    let mut ticker = tokio::time::interval(heartbeat.interval);
+    // spoofed packets must not turn the port into an amplifier, so addresses that never
+    // said hello get few answers and none larger than what they sent
+    let mut stranger_replies = 0;
     loop {
-        let (len, addr) = match socket.recv_from(&mut buf).await {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = ticker.tick() => {
+                stranger_replies = 0;
+                evict_silent_clients(&mut clients, &socket, heartbeat.timeout).await;
+                send_heartbeats(&clients, &socket).await;
+                continue;
+            }
+        };
+        let (len, addr) = match received {
             Ok(res) => res,
             Err(e) => {
                 error!("Error receiving data: {:?}", e);
@@ -44,36 +287,52 @@ pub async fn server_loop(socket: UdpSocket) {
         let mut is_new_client = true;
         for client in &mut clients {
             if client.addr == addr {
-                client.last_active = std::time::Instant::now();
+                client.last_active = Instant::now();
                 is_new_client = false;
             }
         }
-        if is_new_client {
-            info!("New client connected: {}", addr);
-            clients.push(ClientInfo {
-                addr,
-                last_active: std::time::Instant::now(),
-            });
-        }
-        check_counter += 1;
-        if check_counter >= 100 {
-            let now = std::time::Instant::now();
-            let to_remove: Vec<std::net::SocketAddr> = clients
-                .iter()
-                .filter(|client| now.duration_since(client.last_active).as_secs() >= 500)
-                .map(|client| client.addr)
-                .collect();
-            for addr in &to_remove {
-                remove_client(&mut clients, addr, &socket).await;
-            }
-            debug!(
-                "Cleaned up inactive clients. Before: {}, After: {}",
-                to_remove.len(),
-                clients.len()
+        let version = clients
+            .iter()
+            .find(|client| client.addr == addr)
+            .map_or(PROTOCOL_VERSION, |client| client.version);
+        let msg = match decode_message(&buf[..len]) {
+            Ok(msg) => msg,
+            // whatever else is sent to this port doesn't get an answer
+            Err(DecodeError::BadMagic) => {
+                debug!("Ignoring stray packet from {}", addr);
+                continue;
+            }
+            Err(e) if is_new_client => {
+                debug!("Ignoring bad packet from unknown {}: {}", addr, e);
+                continue;
+            }
+            Err(e) => {
+                warn!("Bad packet from {}: {}", addr, e);
+                send_error(&socket, addr, version, e.code(), e.to_string()).await;
+                continue;
+            }
+        };
+        // only a handshake makes a client known, until then we don't know what it speaks.
+        // A client we forgot, e.g. after a restart, learns that it has to say hello again.
+        if is_new_client && !matches!(msg, Message::Hello(_)) {
+            let reply = encode_message(
+                &Message::Error {
+                    code: ERROR_NOT_CONNECTED,
+                    reason: "say hello".to_string(),
+                },
+                version,
             );
-            check_counter = 0;
+            if reply.len() > len || stranger_replies >= MAX_STRANGER_REPLIES {
+                debug!("Ignoring message from {} before its hello", addr);
+                continue;
+            }
+            debug!("Message from {} before its hello", addr);
+            stranger_replies += 1;
+            if let Err(e) = socket.send_to(&reply, addr).await {
+                error!("Error sending error to {}: {:?}", addr, e);
+            }
+            continue;
         }
-        let msg = decode_message(&buf[..len]);
         match msg {
             Message::Audio(data) => {
                 debug!(
@@ -82,9 +341,9 @@ pub async fn server_loop(socket: UdpSocket) {
                     addr
                 );
                 let msg = Message::AudioFrom(addr, data);
-                let buf = encode_message(&msg);
                 for client in &clients {
-                    if client.addr != addr {
+                    if client.addr != addr && client.wants(&msg) {
+                        let buf = encode_message(&msg, client.version);
                         match socket.send_to(&buf, client.addr).await {
                             Ok(_) => println!("Forwarded audio packet to {}", client.addr),
                             Err(e) => error!("Error forwarding audio to {}: {:?}", client.addr, e),
@@ -93,44 +352,85 @@ pub async fn server_loop(socket: UdpSocket) {
                 }
                 // Here you would handle the audio data, e.g., play it or forward it
             }
-            Message::Ping => {
-                debug!("Received ping from {}", addr);
-                // Handle ping
+            Message::Report(report) => {
+                debug!(
+                    "Received report from {} about {}: {}% loss",
+                    addr, report.source, report.loss_percent
+                );
+                let msg = Message::ReportFrom(addr, report);
+                let Some(source) = clients
+                    .iter()
+                    .find(|client| client.addr == report.source && client.wants(&msg))
+                else {
+                    continue;
+                };
+                let buf = encode_message(&msg, source.version);
+                if let Err(e) = socket.send_to(&buf, report.source).await {
+                    error!("Error forwarding report to {}: {:?}", report.source, e);
+                }
+            }
+            Message::Ping { nonce, timestamp } => {
+                debug!("Received ping {} from {}", nonce, addr);
+                let pong = encode_message(&Message::Pong { nonce, timestamp }, version);
+                if let Err(e) = socket.send_to(&pong, addr).await {
+                    error!("Error sending pong to {}: {:?}", addr, e);
+                }
             }
-            Message::Hello(text) => {
-                info!("Received hello from {}: {}", addr, text);
-                // send all clients the new client's hello message
+            Message::Hello(hello) => {
+                info!("Received hello from {}: {:?}", addr, hello);
+                let version = match hello.negotiate() {
+                    Ok(version) => version,
+                    Err(reason) => {
+                        warn!("Rejecting {}: {}", addr, reason);
+                        let msg = encode_message(&Message::Rejected(reason), PROTOCOL_VERSION);
+                        if let Err(e) = socket.send_to(&msg, addr).await {
+                            error!("Error sending rejection to {}: {:?}", addr, e);
+                        }
+                        continue;
+                    }
+                };
+                let capabilities = hello.capabilities & CAPABILITIES;
+                let welcome = Message::Welcome {
+                    version,
+                    capabilities,
+                };
+                // the client says hello a few times, every one of them gets an answer
                 match socket
-                    .send_to(&encode_message(&Message::Hello(text)), addr)
+                    .send_to(&encode_message(&welcome, version), addr)
                     .await
                 {
-                    Ok(_) => debug!("Sent hello ack to {}", addr),
-                    Err(e) => error!("Error sending hello ack to {}: {:?}", addr, e),
+                    Ok(_) => debug!("Sent welcome with version {} to {}", version, addr),
+                    Err(e) => error!("Error sending welcome to {}: {:?}", addr, e),
                 }
-                // if client list already contains the addr, don't notify others
-                if is_new_client {
-                    debug!("Got new client {}", addr);
-                    // Notify other clients about the new client, and the new client about existing clients
-                    for client in &clients {
-                        if client.addr != addr {
-                            // Notify existing clients about the new client
-                            let new_client_msg = encode_message(&Message::NewClient(addr));
-                            match socket.send_to(&new_client_msg, client.addr).await {
-                                Ok(_) => debug!("Sent new client message to {}", client.addr),
-                                Err(e) => {
-                                    error!(
-                                        "Error sending new client msg to {}: {:?}",
-                                        client.addr, e
-                                    )
-                                }
+                if !is_new_client {
+                    continue;
+                }
+                info!("New client connected: {}", addr);
+                clients.push(ClientInfo {
+                    addr,
+                    last_active: Instant::now(),
+                    version,
+                    capabilities,
+                });
+                // Notify other clients about the new client, and the new client about existing clients
+                for client in &clients {
+                    if client.addr != addr {
+                        // Notify existing clients about the new client
+                        let new_client_msg =
+                            encode_message(&Message::NewClient(addr), client.version);
+                        match socket.send_to(&new_client_msg, client.addr).await {
+                            Ok(_) => debug!("Sent new client message to {}", client.addr),
+                            Err(e) => {
+                                error!("Error sending new client msg to {}: {:?}", client.addr, e)
                             }
+                        }
 
-                            let new_client_msg = encode_message(&Message::NewClient(client.addr));
-                            match socket.send_to(&new_client_msg, addr).await {
-                                Ok(_) => debug!("Sent new client message to {}", addr),
-                                Err(e) => {
-                                    error!("Error sending new client msg to {}: {:?}", addr, e)
-                                }
+                        let new_client_msg =
+                            encode_message(&Message::NewClient(client.addr), version);
+                        match socket.send_to(&new_client_msg, addr).await {
+                            Ok(_) => debug!("Sent new client message to {}", addr),
+                            Err(e) => {
+                                error!("Error sending new client msg to {}: {:?}", addr, e)
                             }
                         }
                     }
@@ -138,72 +438,360 @@ pub async fn server_loop(socket: UdpSocket) {
             }
             Message::Bye => {
                 info!("Received bye from {}", addr);
-                remove_client(&mut clients, &addr, &socket).await;
-            }
-            Message::Unknown(data) => {
-                warn!(
-                    "Received unknown message type from {}: {} bytes",
-                    addr,
-                    data.len()
-                );
+                remove_client(&mut clients, &addr, &socket, Message::Bye).await;
             }
             _ => {}
         }
     }
 }
 
-fn contains_client(clients: &Vec<ClientInfo>, addr: &SocketAddr) -> bool {
-    for client in clients {
-        if &client.addr == addr {
-            return true;
+/// Kicks the clients we haven't heard from in `timeout`
+async fn evict_silent_clients(
+    clients: &mut Vec<ClientInfo>,
+    socket: &UdpSocket,
+    timeout: Duration,
+) {
+    let now = Instant::now();
+    let silent: Vec<(std::net::SocketAddr, Duration)> = clients
+        .iter()
+        .filter_map(|client| {
+            let timeout = if client.version >= PING_VERSION {
+                timeout
+            } else {
+                LEGACY_TIMEOUT
+            };
+            let silent_for = now.duration_since(client.last_active);
+            (silent_for >= timeout).then_some((client.addr, silent_for))
+        })
+        .collect();
+    for (addr, silent_for) in silent {
+        info!("Evicting {}, silent for {:?}", addr, silent_for);
+        let kicked = Message::Kicked {
+            reason: format!("nothing heard for {} s", silent_for.as_secs()),
+        };
+        remove_client(clients, &addr, socket, kicked).await;
+    }
+}
+
+async fn send_heartbeats(clients: &[ClientInfo], socket: &UdpSocket) {
+    for client in clients
+        .iter()
+        .filter(|client| client.version >= PING_VERSION)
+    {
+        let msg = encode_message(&Message::Heartbeat, client.version);
+        if let Err(e) = socket.send_to(&msg, client.addr).await {
+            error!("Error sending heartbeat to {}: {:?}", client.addr, e);
         }
     }
-    return false;
 }
 
+/// Drops a client and tells the others, `farewell` is `Bye` when it asked to leave and
+/// `Kicked` otherwise
 async fn remove_client(
     clients: &mut Vec<ClientInfo>,
     addr: &std::net::SocketAddr,
     socket: &UdpSocket,
+    farewell: Message,
 ) {
-    let size_before = clients.len();
-    clients.retain(|client| {
-        if &client.addr == addr {
-            debug!("Removing client {}", addr);
-            false
-        } else {
-            true
-        }
-    });
-    if clients.len() < size_before {
-        let bye_msg = encode_message(&Message::Bye);
-        match socket.send_to(&bye_msg, addr).await {
-            Ok(_) => debug!("Sent bye message to {}", addr),
-            Err(e) => error!("Error sending bye message to {}: {:?}", addr, e),
-        }
-        for client in clients.iter() {
-            let delete_msg = encode_message(&Message::DeleteClient(*addr));
-            match socket.send_to(&delete_msg, client.addr).await {
-                Ok(_) => debug!("Sent delete client message to {}", client.addr),
-                Err(e) => error!(
-                    "Error sending delete client msg to {}: {:?}",
-                    client.addr, e
-                ),
-            }
+    let Some(index) = clients.iter().position(|client| &client.addr == addr) else {
+        return;
+    };
+    debug!("Removing client {}: {:?}", addr, farewell);
+    let removed = clients.remove(index);
+    let bye_msg = encode_message(&farewell, removed.version);
+    match socket.send_to(&bye_msg, addr).await {
+        Ok(_) => debug!("Sent bye message to {}", addr),
+        Err(e) => error!("Error sending bye message to {}: {:?}", addr, e),
+    }
+    for client in clients.iter() {
+        let delete_msg = encode_message(&Message::DeleteClient(*addr), client.version);
+        match socket.send_to(&delete_msg, client.addr).await {
+            Ok(_) => debug!("Sent delete client message to {}", client.addr),
+            Err(e) => error!(
+                "Error sending delete client msg to {}: {:?}",
+                client.addr, e
+            ),
         }
     }
 }
 
-pub fn decode_message(buf: &[u8]) -> Message {
-    if buf.is_empty() {
-        return Message::Unknown(Vec::new());
+async fn send_error(
+    socket: &UdpSocket,
+    addr: std::net::SocketAddr,
+    version: u8,
+    code: u16,
+    reason: String,
+) {
+    let msg = encode_message(&Message::Error { code, reason }, version);
+    if let Err(e) = socket.send_to(&msg, addr).await {
+        error!("Error sending error to {}: {:?}", addr, e);
+    }
+}
+
+/// Parses a packet, one we can't understand comes back as the reason why
+pub fn decode_message(buf: &[u8]) -> Result<Message, DecodeError> {
+    if buf.len() < MAGIC.len() || buf[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    if buf.len() < HEADER_LEN {
+        return Err(DecodeError::TooShort(buf.len()));
+    }
+    let (version, message_type) = (buf[2], buf[3]);
+    let payload = &buf[HEADER_LEN..];
+    let any_version = matches!(
+        message_type,
+        TYPE_HELLO | TYPE_WELCOME | TYPE_REJECTED | TYPE_ERROR
+    );
+    if !any_version && !(OLDEST_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    // every version we speak shares these payloads, once one changes `version` picks the
+    // layout here and in `encode_message`. Version 1 pings had none, but nothing sent them.
+    let msg = match message_type {
+        TYPE_AUDIO => take(payload).map(Message::Audio),
+        TYPE_AUDIO_FROM => take(payload).map(|(addr, audio)| Message::AudioFrom(addr, audio)),
+        TYPE_PING => take(payload).map(|(nonce, timestamp)| Message::Ping { nonce, timestamp }),
+        TYPE_PONG => take(payload).map(|(nonce, timestamp)| Message::Pong { nonce, timestamp }),
+        TYPE_HEARTBEAT => Ok(Message::Heartbeat),
+        TYPE_HELLO => take(payload).map(Message::Hello),
+        TYPE_WELCOME => take(payload).map(|(version, capabilities)| Message::Welcome {
+            version,
+            capabilities,
+        }),
+        TYPE_REJECTED => take(payload).map(Message::Rejected),
+        TYPE_NEW_CLIENT => take(payload).map(Message::NewClient),
+        TYPE_DELETE_CLIENT => take(payload).map(Message::DeleteClient),
+        TYPE_BYE => Ok(Message::Bye),
+        TYPE_REPORT => take(payload).map(Message::Report),
+        TYPE_REPORT_FROM => take(payload).map(|(addr, report)| Message::ReportFrom(addr, report)),
+        TYPE_ERROR => take(payload).map(|(code, reason)| Message::Error { code, reason }),
+        TYPE_KICKED => take(payload).map(|reason| Message::Kicked { reason }),
+        _ => return Err(DecodeError::UnknownType(message_type)),
+    };
+    msg.map_err(|e| DecodeError::Payload(message_type, e))
+}
+
+/// Frames `msg` for a peer that speaks `version`
+pub fn encode_message(msg: &Message, version: u8) -> Vec<u8> {
+    let message_type = match msg {
+        Message::Audio(_) => TYPE_AUDIO,
+        Message::AudioFrom(..) => TYPE_AUDIO_FROM,
+        Message::Ping { .. } => TYPE_PING,
+        Message::Pong { .. } => TYPE_PONG,
+        Message::Heartbeat => TYPE_HEARTBEAT,
+        Message::Hello(_) => TYPE_HELLO,
+        Message::Welcome { .. } => TYPE_WELCOME,
+        Message::Rejected(_) => TYPE_REJECTED,
+        Message::NewClient(_) => TYPE_NEW_CLIENT,
+        Message::DeleteClient(_) => TYPE_DELETE_CLIENT,
+        Message::Bye => TYPE_BYE,
+        Message::Report(_) => TYPE_REPORT,
+        Message::ReportFrom(..) => TYPE_REPORT_FROM,
+        Message::Error { .. } => TYPE_ERROR,
+        Message::Kicked { .. } => TYPE_KICKED,
+    };
+    let mut buf = Vec::with_capacity(64);
+    buf.extend_from_slice(&MAGIC);
+    buf.extend_from_slice(&[version, message_type, 0]);
+    match msg {
+        Message::Audio(audio) => put(&mut buf, audio),
+        Message::AudioFrom(addr, audio) => put(&mut buf, (addr, audio)),
+        Message::Hello(hello) => put(&mut buf, hello),
+        Message::Welcome {
+            version,
+            capabilities,
+        } => put(&mut buf, (version, capabilities)),
+        Message::Rejected(reason) => put(&mut buf, reason),
+        Message::NewClient(addr) | Message::DeleteClient(addr) => put(&mut buf, addr),
+        Message::Report(report) => put(&mut buf, report),
+        Message::ReportFrom(addr, report) => put(&mut buf, (addr, report)),
+        Message::Ping { nonce, timestamp } | Message::Pong { nonce, timestamp } => {
+            put(&mut buf, (nonce, timestamp))
+        }
+        Message::Bye | Message::Heartbeat => {}
+        Message::Error { code, reason } => put(&mut buf, (code, reason)),
+        Message::Kicked { reason } => put(&mut buf, reason),
     }
+    buf
+}
 
-    return bincode::decode_from_slice(buf, config::standard())
-        .map(|(msg, _)| msg)
-        .unwrap_or(Message::Unknown(buf.to_vec()));
+fn put<T: Encode>(buf: &mut Vec<u8>, value: T) {
+    bincode::encode_into_std_write(value, buf, config::standard()).unwrap();
 }
 
-pub fn encode_message(msg: &Message) -> Vec<u8> {
-    bincode::encode_to_vec(msg, config::standard()).unwrap()
+fn take<T: Decode<()>>(payload: &[u8]) -> Result<T, bincode::error::DecodeError> {
+    bincode::decode_from_slice(payload, config::standard()).map(|(value, _)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> std::net::SocketAddr {
+        "192.0.2.1:4000".parse().unwrap()
+    }
+
+    fn audio() -> AudioData {
+        AudioData {
+            stream_id: SHARE_STREAM,
+            timestamp: 1234,
+            seq_number: 42,
+            channels: 2,
+            frame_ms: 20,
+            data: vec![1, 2, 3],
+        }
+    }
+
+    fn report() -> ReceiverReport {
+        ReceiverReport {
+            source: addr(),
+            stream_id: VOICE_STREAM,
+            loss_percent: 7,
+        }
+    }
+
+    fn hello(min_version: u8, max_version: u8) -> Hello {
+        Hello {
+            min_version,
+            max_version,
+            capabilities: CAPABILITIES,
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let messages = [
+            (TYPE_AUDIO, Message::Audio(audio())),
+            (TYPE_AUDIO_FROM, Message::AudioFrom(addr(), audio())),
+            (
+                TYPE_PING,
+                Message::Ping {
+                    nonce: 3,
+                    timestamp: 99,
+                },
+            ),
+            (TYPE_HELLO, Message::Hello(Hello::ours())),
+            (
+                TYPE_WELCOME,
+                Message::Welcome {
+                    version: PROTOCOL_VERSION,
+                    capabilities: CAP_SHARE_STREAM,
+                },
+            ),
+            (TYPE_REJECTED, Message::Rejected("full".to_string())),
+            (TYPE_NEW_CLIENT, Message::NewClient(addr())),
+            (TYPE_DELETE_CLIENT, Message::DeleteClient(addr())),
+            (TYPE_BYE, Message::Bye),
+            (TYPE_REPORT, Message::Report(report())),
+            (TYPE_REPORT_FROM, Message::ReportFrom(addr(), report())),
+            (
+                TYPE_ERROR,
+                Message::Error {
+                    code: ERROR_MALFORMED,
+                    reason: "bad".to_string(),
+                },
+            ),
+            (
+                TYPE_KICKED,
+                Message::Kicked {
+                    reason: "timed out".to_string(),
+                },
+            ),
+            (
+                TYPE_PONG,
+                Message::Pong {
+                    nonce: 3,
+                    timestamp: 99,
+                },
+            ),
+            (TYPE_HEARTBEAT, Message::Heartbeat),
+        ];
+        for (message_type, msg) in messages {
+            let buf = encode_message(&msg, PROTOCOL_VERSION);
+            assert_eq!(buf[..MAGIC.len()], MAGIC);
+            assert_eq!(buf[2], PROTOCOL_VERSION);
+            assert_eq!(buf[3], message_type);
+            assert_eq!(decode_message(&buf).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn rejects_foreign_packets() {
+        assert!(matches!(decode_message(b""), Err(DecodeError::BadMagic)));
+        assert!(matches!(decode_message(b"K"), Err(DecodeError::BadMagic)));
+        assert!(matches!(
+            decode_message(b"XY\x02\x09\x00"),
+            Err(DecodeError::BadMagic)
+        ));
+        assert!(matches!(
+            decode_message(b"KA\x02"),
+            Err(DecodeError::TooShort(3))
+        ));
+    }
+
+    #[test]
+    fn rejects_unsupported_versions_and_types() {
+        let mut buf = encode_message(&Message::Bye, PROTOCOL_VERSION + 1);
+        let e = decode_message(&buf).unwrap_err();
+        assert!(matches!(e, DecodeError::UnsupportedVersion(v) if v == PROTOCOL_VERSION + 1));
+        assert_eq!(e.code(), ERROR_UNSUPPORTED_VERSION);
+
+        buf[2] = PROTOCOL_VERSION;
+        buf[3] = 200;
+        let e = decode_message(&buf).unwrap_err();
+        assert!(matches!(e, DecodeError::UnknownType(200)));
+        assert_eq!(e.code(), ERROR_MALFORMED);
+    }
+
+    #[test]
+    fn handshake_decodes_at_any_version() {
+        // a peer too new or too old still learns why it can't talk to us
+        for version in [0, PROTOCOL_VERSION + 1, u8::MAX] {
+            for msg in [
+                Message::Hello(hello(version, version)),
+                Message::Welcome {
+                    version,
+                    capabilities: 0,
+                },
+                Message::Rejected("incompatible".to_string()),
+                Message::Error {
+                    code: ERROR_UNSUPPORTED_VERSION,
+                    reason: "too new".to_string(),
+                },
+            ] {
+                assert_eq!(decode_message(&encode_message(&msg, version)).unwrap(), msg);
+            }
+        }
+    }
+
+    #[test]
+    fn negotiates_the_newest_common_version() {
+        assert_eq!(
+            hello(OLDEST_VERSION, PROTOCOL_VERSION).negotiate(),
+            Ok(PROTOCOL_VERSION)
+        );
+        // a newer peer, and an older one, that overlap with us in a single version
+        assert_eq!(
+            hello(PROTOCOL_VERSION, PROTOCOL_VERSION + 1).negotiate(),
+            Ok(PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            hello(OLDEST_VERSION - 1, OLDEST_VERSION).negotiate(),
+            Ok(OLDEST_VERSION)
+        );
+    }
+
+    #[test]
+    fn refuses_disjoint_versions() {
+        assert!(
+            hello(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2)
+                .negotiate()
+                .is_err()
+        );
+        assert!(
+            hello(OLDEST_VERSION - 1, OLDEST_VERSION - 1)
+                .negotiate()
+                .is_err()
+        );
+    }
 }
@@ -2,7 +2,14 @@ use log::debug;
 use ratatui::{
     DefaultTerminal, Frame,
     buffer::Buffer,
-    crossterm::event::{self, Event, KeyEventKind},
+    crossterm::{
+        event::{
+            self, Event, KeyEventKind, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+            PushKeyboardEnhancementFlags,
+        },
+        execute,
+        terminal::supports_keyboard_enhancement,
+    },
     layout::{Constraint, Layout, Rect},
     style::Stylize,
     symbols::border,
@@ -16,34 +23,76 @@ use std::{
         Arc,
         mpsc::{Receiver, Sender},
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
     ClientState,
     client::{self, ClientMessage},
+    implementations::Device,
 };
 
+// without key release events a held key is only visible through its auto repeat, which
+// starts after about half a second on most systems
+const PTT_REPEAT_TIMEOUT: Duration = Duration::from_millis(650);
+// shared audio skips silence, so a sender counts as sharing until it was quiet this long
+const SHARING_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[derive(Debug)]
 pub struct App {
     client_state: ClientState,
 
+    // push to talk mode, <Space> has to be held to send audio
+    ptt: bool,
+    // terminal reports key releases, otherwise releases are guessed from missing repeats
+    key_release_events: bool,
+    ptt_deadline: Option<Instant>,
+
     main_widget: UserListWidget,
+    // opened with <I> or <O>, shown instead of the user list
+    device_picker: Option<DevicePicker>,
 
     rx: Receiver<client::ClientMessage>,
     tx_coordinator: Sender<client::ClientMessage>,
 }
 
 impl App {
-    pub fn new(rx: Receiver<client::ClientMessage>, tx_coordinator: Sender<client::ClientMessage>) {
+    pub fn new(
+        rx: Receiver<client::ClientMessage>,
+        tx_coordinator: Sender<client::ClientMessage>,
+        ptt: bool,
+        noise_suppression: bool,
+        share: bool,
+    ) {
         let mut app = App {
-            client_state: ClientState::default(),
+            client_state: ClientState {
+                noise_suppression,
+                share,
+                ..Default::default()
+            },
+            ptt,
+            key_release_events: false,
+            ptt_deadline: None,
             rx,
             tx_coordinator,
-            main_widget: UserListWidget { users: vec![] },
+            main_widget: UserListWidget {
+                users: vec![],
+                selected: 0,
+            },
+            device_picker: None,
         };
         let terminal = ratatui::init();
+        if ptt && supports_keyboard_enhancement().unwrap_or(false) {
+            app.key_release_events = execute!(
+                std::io::stdout(),
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )
+            .is_ok();
+        }
         let result = app.run(terminal);
+        if app.key_release_events {
+            let _ = execute!(std::io::stdout(), PopKeyboardEnhancementFlags);
+        }
         ratatui::restore();
     }
 
@@ -61,6 +110,13 @@ impl App {
             if set_speaking_flags(&mut self.main_widget.users) {
                 should_draw = true;
             }
+            if self
+                .ptt_deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                self.release_ptt();
+                should_draw = true;
+            }
         }
         Ok(())
     }
@@ -72,7 +128,10 @@ impl App {
             .spacing(-1)
             .split(frame.area());
         frame.render_widget(self, layout[0]);
-        frame.render_widget(&self.main_widget, layout[1]);
+        match &self.device_picker {
+            Some(picker) => frame.render_widget(picker, layout[1]),
+            None => frame.render_widget(&self.main_widget, layout[1]),
+        }
     }
 
     fn handle_tui_messages(&mut self) -> bool {
@@ -81,37 +140,108 @@ impl App {
             match message {
                 client::ClientMessage::Connect => {
                     self.client_state.connected = true;
+                    self.client_state.server_error = None;
+                }
+                client::ClientMessage::Rejected(reason) => {
+                    self.client_state.connected = false;
+                    self.client_state.server_error = Some(format!("Rejected: {}", reason));
+                }
+                client::ClientMessage::NoServers => {
+                    self.client_state.connected = false;
+                    self.client_state.server = None;
+                    // keeps the last server's reason next to it
+                    let last = self.client_state.server_error.take();
+                    self.client_state.server_error = Some(match last {
+                        Some(last) => format!("Every server rejected us ({})", last),
+                        None => "Every server rejected us".to_string(),
+                    });
+                }
+                client::ClientMessage::ServerError(_, reason) => {
+                    self.client_state.server_error = Some(format!("Server error: {}", reason));
+                }
+                client::ClientMessage::Kicked(reason) => {
+                    self.client_state.connected = false;
+                    self.client_state.sending_audio = false;
+                    self.client_state.server_error = Some(format!("Kicked: {}", reason));
+                }
+                client::ClientMessage::Connecting(server) => {
+                    self.client_state.connected = false;
+                    self.client_state.server = Some(server);
                 }
                 client::ClientMessage::Disconnect => {
                     self.client_state.connected = false;
+                    self.client_state.rtt = None;
                     self.client_state.sending_audio = false;
+                    // the next server tells us who is there
+                    self.main_widget.users.clear();
+                    self.main_widget.selected = 0;
+                }
+                client::ClientMessage::Rtt(stats) => {
+                    self.client_state.rtt = Some(stats);
+                }
+                client::ClientMessage::DeviceLatency { input, output } => {
+                    self.client_state.input_latency = input;
+                    self.client_state.output_latency = output;
                 }
                 client::ClientMessage::TransmitAudio(sending) => {
                     self.client_state.sending_audio = sending;
                 }
+                client::ClientMessage::InputGain(gain_db) => {
+                    self.client_state.input_gain_db = gain_db;
+                }
+                ClientMessage::Devices(list) => {
+                    if let Some(picker) = self.device_picker.as_mut() {
+                        picker.devices = Some(match picker.side {
+                            DeviceSide::Input => {
+                                list.sources.into_iter().chain(list.monitors).collect()
+                            }
+                            DeviceSide::Output => list.sinks,
+                        });
+                    }
+                }
+                ClientMessage::ShareFailed(_) => self.client_state.share = false,
+                ClientMessage::InputLost => self.client_state.input_lost = true,
+                ClientMessage::OutputLost => self.client_state.output_lost = true,
+                ClientMessage::InputRestored => self.client_state.input_lost = false,
+                ClientMessage::OutputRestored => self.client_state.output_lost = false,
+                ClientMessage::DeviceStatus(status) => {
+                    // listing failed, there's nothing to pick from
+                    if self
+                        .device_picker
+                        .as_ref()
+                        .is_some_and(|picker| picker.devices.is_none())
+                    {
+                        self.device_picker = None;
+                    }
+                    self.client_state.device_status = Some(status);
+                }
                 client::ClientMessage::NewClient(addr) => {
                     self.main_widget.users.push(UserListEntry {
-                        addr: addr.to_string(),
+                        addr,
                         is_speaking: false,
                         last_spoke: None,
+                        last_shared: None,
+                        share_muted: false,
                     });
                 }
                 client::ClientMessage::DeleteClient(addr) => {
-                    self.main_widget
-                        .users
-                        .retain(|user| user.addr != addr.to_string());
+                    self.main_widget.users.retain(|user| user.addr != addr);
+                    self.main_widget.selected = self
+                        .main_widget
+                        .selected
+                        .min(self.main_widget.users.len().saturating_sub(1));
                 }
                 ClientMessage::ShowActive(addr) => {
-                    if let Some(user) = self
-                        .main_widget
-                        .users
-                        .iter_mut()
-                        .find(|user| user.addr == addr.to_string())
-                    {
+                    if let Some(user) = self.main_widget.user_mut(addr) {
                         user.is_speaking = true;
                         user.last_spoke = Some(std::time::Instant::now());
                     }
                 }
+                ClientMessage::ShowSharing(addr) => {
+                    if let Some(user) = self.main_widget.user_mut(addr) {
+                        user.last_shared = Some(Instant::now());
+                    }
+                }
                 _ => {}
             }
             updated = true;
@@ -121,9 +251,26 @@ impl App {
 
     fn handle_event(&mut self, event: Event) {
         match event {
+            Event::Key(key_event) if self.ptt && key_event.code == event::KeyCode::Char(' ') => {
+                match key_event.kind {
+                    KeyEventKind::Press | KeyEventKind::Repeat => {
+                        if !self.client_state.ptt_pressed {
+                            self.client_state.ptt_pressed = true;
+                            let _ = self.tx_coordinator.send(client::ClientMessage::PttPress);
+                        }
+                        if !self.key_release_events {
+                            self.ptt_deadline = Some(Instant::now() + PTT_REPEAT_TIMEOUT);
+                        }
+                    }
+                    KeyEventKind::Release => self.release_ptt(),
+                }
+            }
             // it's important to check that the event is a key press event as
             // crossterm also emits key release and repeat events on Windows.
             Event::Key(key_event) if key_event.kind == KeyEventKind::Press => {
+                if self.handle_picker_key(key_event.code) {
+                    return;
+                }
                 match key_event.code {
                     event::KeyCode::Char('d') | event::KeyCode::Char('D') => {
                         self.client_state.deafen = !self.client_state.deafen;
@@ -135,6 +282,40 @@ impl App {
                         self.client_state.mute = !self.client_state.mute;
                         let _ = self.tx_coordinator.send(client::ClientMessage::ToggleMute);
                     }
+                    event::KeyCode::Char('n') | event::KeyCode::Char('N') => {
+                        self.client_state.noise_suppression = !self.client_state.noise_suppression;
+                        let _ = self
+                            .tx_coordinator
+                            .send(client::ClientMessage::ToggleNoiseSuppression);
+                    }
+                    event::KeyCode::Char('i') | event::KeyCode::Char('I') => {
+                        self.open_device_picker(DeviceSide::Input);
+                    }
+                    event::KeyCode::Char('o') | event::KeyCode::Char('O') => {
+                        self.open_device_picker(DeviceSide::Output);
+                    }
+                    event::KeyCode::Char('s') | event::KeyCode::Char('S')
+                        if self.client_state.share =>
+                    {
+                        self.client_state.share_paused = !self.client_state.share_paused;
+                        let _ = self.tx_coordinator.send(client::ClientMessage::ToggleShare);
+                    }
+                    event::KeyCode::Char('a') | event::KeyCode::Char('A') => {
+                        let selected = self.main_widget.selected;
+                        if let Some(user) = self.main_widget.users.get_mut(selected) {
+                            user.share_muted = !user.share_muted;
+                            let _ = self
+                                .tx_coordinator
+                                .send(client::ClientMessage::ToggleShareMute(user.addr));
+                        }
+                    }
+                    event::KeyCode::Up | event::KeyCode::Char('k') => {
+                        self.main_widget.selected = self.main_widget.selected.saturating_sub(1);
+                    }
+                    event::KeyCode::Down | event::KeyCode::Char('j') => {
+                        self.main_widget.selected = (self.main_widget.selected + 1)
+                            .min(self.main_widget.users.len().saturating_sub(1));
+                    }
                     event::KeyCode::Char('q') | event::KeyCode::Char('Q') => {
                         self.client_state.exit = true;
                         let _ = self.tx_coordinator.send(client::ClientMessage::Exit);
@@ -146,12 +327,71 @@ impl App {
             _ => {}
         };
     }
+
+    fn open_device_picker(&mut self, side: DeviceSide) {
+        self.device_picker = Some(DevicePicker {
+            side,
+            devices: None,
+            selected: 0,
+        });
+        let _ = self.tx_coordinator.send(client::ClientMessage::ListDevices);
+    }
+
+    /// Moves through the open device picker, returns false for keys it doesn't use
+    fn handle_picker_key(&mut self, code: event::KeyCode) -> bool {
+        let Some(picker) = self.device_picker.as_mut() else {
+            return false;
+        };
+        let entries = picker
+            .devices
+            .as_ref()
+            .map_or(0, |devices| devices.len() + 1);
+        match code {
+            event::KeyCode::Up | event::KeyCode::Char('k') => {
+                picker.selected = picker.selected.saturating_sub(1);
+            }
+            event::KeyCode::Down | event::KeyCode::Char('j') => {
+                picker.selected = (picker.selected + 1).min(entries.saturating_sub(1));
+            }
+            event::KeyCode::Enter if entries > 0 => {
+                let device = picker
+                    .devices
+                    .as_ref()
+                    .and_then(|devices| devices.get(picker.selected.checked_sub(1)?))
+                    .map(|device| device.name.clone());
+                let message = match picker.side {
+                    DeviceSide::Input => client::ClientMessage::SwitchInput(device),
+                    DeviceSide::Output => client::ClientMessage::SwitchOutput(device),
+                };
+                let _ = self.tx_coordinator.send(message);
+                self.device_picker = None;
+            }
+            event::KeyCode::Esc => self.device_picker = None,
+            _ => return false,
+        }
+        true
+    }
+
+    fn release_ptt(&mut self) {
+        self.ptt_deadline = None;
+        if self.client_state.ptt_pressed {
+            self.client_state.ptt_pressed = false;
+            let _ = self.tx_coordinator.send(client::ClientMessage::PttRelease);
+        }
+    }
 }
 
 fn set_speaking_flags(users: &mut Vec<UserListEntry>) -> bool {
     let mut updated = false;
     let now = std::time::Instant::now();
     for user in users.iter_mut() {
+        if user
+            .last_shared
+            .is_some_and(|last_shared| now.duration_since(last_shared) > SHARING_TIMEOUT)
+        {
+            user.last_shared = None;
+            updated = true;
+        }
         if user.is_speaking {
             if let Some(last_spoke) = user.last_spoke {
                 if now.duration_since(last_spoke) > Duration::from_millis(500) {
@@ -169,11 +409,27 @@ impl Widget for &App {
         let mut status_line = vec![" WapplaTalk ".bold()];
         let mutOrDeafen = self.client_state.mute || self.client_state.deafen;
         status_line.push("| ".into());
+        let server = self.client_state.server.as_deref().unwrap_or_default();
         if self.client_state.connected {
-            status_line.push("Connected ".green())
+            status_line.push(format!("Connected to {} ", server).green());
+            if let Some(rtt) = &self.client_state.rtt {
+                status_line
+                    .push(format!("RTT {:.0} ms ±{:.0} ", rtt.rtt_ms, rtt.variance_ms).into());
+            }
+        } else if self.client_state.server.is_some() {
+            status_line.push(format!("Connecting to {} ", server).yellow());
         } else {
             status_line.push("Disconnected ".red())
         };
+        if let Some(input) = self.client_state.input_latency {
+            status_line.push(format!("In {} ms ", input.as_millis()).into());
+        }
+        if let Some(output) = self.client_state.output_latency {
+            status_line.push(format!("Out {} ms ", output.as_millis()).into());
+        }
+        if let Some(error) = &self.client_state.server_error {
+            status_line.push(format!("{} ", error).red().bold());
+        }
         if mutOrDeafen {
             status_line.push("(".into());
         }
@@ -190,6 +446,42 @@ impl Widget for &App {
             status_line.push(" ) ".into());
         }
         status_line.push("| ".into());
+        if self.client_state.noise_suppression {
+            status_line.push("Denoise ".green());
+        } else {
+            status_line.push("Denoise off ".into());
+        }
+        status_line.push("| ".into());
+        status_line.push(format!("Gain {:+.1} dB ", self.client_state.input_gain_db).into());
+        status_line.push("| ".into());
+        if self.client_state.input_lost {
+            status_line.push("Microphone lost ".red().bold());
+            status_line.push("| ".into());
+        }
+        if self.client_state.output_lost {
+            status_line.push("Speakers lost ".red().bold());
+            status_line.push("| ".into());
+        }
+        if let Some(status) = &self.client_state.device_status {
+            status_line.push(format!("{} ", status).yellow());
+            status_line.push("| ".into());
+        }
+        if self.client_state.share {
+            if self.client_state.share_paused {
+                status_line.push("Sharing paused ".yellow());
+            } else {
+                status_line.push("Sharing ".green());
+            }
+            status_line.push("| ".into());
+        }
+        if self.ptt {
+            if self.client_state.ptt_pressed {
+                status_line.push("PTT ".green().bold());
+            } else {
+                status_line.push("PTT ".into());
+            }
+            status_line.push("| ".into());
+        }
         if self.client_state.sending_audio {
             status_line.push("Sending Audio ".green())
         } else {
@@ -197,14 +489,34 @@ impl Widget for &App {
         };
 
         let status_line = Line::from(status_line);
-        let instructions = Line::from(vec![
+        let mut instructions = vec![];
+        if self.ptt {
+            instructions.push(" Talk ".into());
+            instructions.push("<Space>".blue().bold());
+        }
+        instructions.extend([
             " Mute ".into(),
             "<M>".blue().bold(),
             " Deafen ".into(),
             "<D>".blue().bold(),
+            " Denoise ".into(),
+            "<N>".blue().bold(),
+            " Input ".into(),
+            "<I>".blue().bold(),
+            " Output ".into(),
+            "<O>".blue().bold(),
+        ]);
+        if self.client_state.share {
+            instructions.push(" Share ".into());
+            instructions.push("<S>".blue().bold());
+        }
+        instructions.extend([
+            " Mute their share ".into(),
+            "<A>".blue().bold(),
             " Quit ".into(),
             "<Q> ".blue().bold(),
         ]);
+        let instructions = Line::from(instructions);
 
         let layout = Layout::default()
             .spacing(1)
@@ -220,13 +532,24 @@ impl Widget for &App {
 #[derive(Debug)]
 struct UserListWidget {
     users: Vec<UserListEntry>,
+    // the user <A> mutes the shared audio of
+    selected: usize,
+}
+
+impl UserListWidget {
+    fn user_mut(&mut self, addr: net::SocketAddr) -> Option<&mut UserListEntry> {
+        self.users.iter_mut().find(|user| user.addr == addr)
+    }
 }
 
 #[derive(Debug)]
 struct UserListEntry {
-    addr: String,
+    addr: net::SocketAddr,
     is_speaking: bool,
     last_spoke: Option<std::time::Instant>,
+    // set while the user shares application audio
+    last_shared: Option<Instant>,
+    share_muted: bool,
 }
 
 impl Widget for &UserListWidget {
@@ -236,11 +559,22 @@ impl Widget for &UserListWidget {
         let user_lines: Vec<Line> = self
             .users
             .iter()
-            .map(|user| {
-                if user.is_speaking {
-                    Line::from(user.addr.as_str().green())
+            .enumerate()
+            .map(|(i, user)| {
+                let mut line = if user.is_speaking {
+                    Line::from(user.addr.to_string().green())
+                } else {
+                    Line::from(user.addr.to_string())
+                };
+                if user.share_muted {
+                    line.push_span(" [share muted]".yellow());
+                } else if user.last_shared.is_some() {
+                    line.push_span(" [sharing]".cyan());
+                }
+                if i == self.selected {
+                    line.reversed()
                 } else {
-                    Line::from(user.addr.as_str())
+                    line
                 }
             })
             .collect();
@@ -249,3 +583,54 @@ impl Widget for &UserListWidget {
         paragraph.render(inner_area, buf);
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DeviceSide {
+    Input,
+    Output,
+}
+
+#[derive(Debug)]
+struct DevicePicker {
+    side: DeviceSide,
+    // None until the coordinator sent the list
+    devices: Option<Vec<Device>>,
+    // 0 is the default device, the listed ones follow
+    selected: usize,
+}
+
+impl Widget for &DevicePicker {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let title = match self.side {
+            DeviceSide::Input => "Input device",
+            DeviceSide::Output => "Output device",
+        };
+        let block = Block::bordered()
+            .title(title)
+            .title_bottom(" Select <Enter> Cancel <Esc> ")
+            .border_set(border::THICK);
+        let inner_area = block.inner(area);
+        let lines: Vec<Line> = match &self.devices {
+            None => vec![Line::from("Asking the sound server...")],
+            Some(devices) => std::iter::once(Line::from("Default device"))
+                .chain(devices.iter().map(|device| {
+                    let mut line = format!("{} ({})", device.description, device.name);
+                    if device.is_default {
+                        line.push_str(" *");
+                    }
+                    Line::from(line)
+                }))
+                .enumerate()
+                .map(|(i, line)| {
+                    if i == self.selected {
+                        line.reversed()
+                    } else {
+                        line
+                    }
+                })
+                .collect(),
+        };
+        block.render(area, buf);
+        Paragraph::new(Text::from(lines)).render(inner_area, buf);
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::VecDeque;
+
+use log::debug;
+
+use crate::{AudioFrame, ErrorKind};
+
+// the noise floor never goes below this, digital silence would make any click look like speech
+const MIN_FLOOR_DB: f32 = -75.0;
+// how fast the floor may rise while nobody talks
+const FLOOR_RISE_DB_PER_SEC: f32 = 1.0;
+// the quietest frame of the last few seconds is background noise, even while talking
+// there are pauses between words. Noise louder than the threshold, like a fan switched
+// on, only gets tracked this way.
+const FLOOR_BLOCK_MS: u32 = 1000;
+const FLOOR_BLOCKS: usize = 5;
+// voiced speech crosses zero rarely, keyboard clicks and hiss cross it a lot
+const MAX_SPEECH_ZCR: f32 = 0.3;
+// part of the calibration frames that are assumed to be background noise
+const CALIBRATION_PERCENTILE: f32 = 0.2;
+
+#[derive(Debug, Clone)]
+pub struct VadSettings {
+    /// How far above the noise floor a frame has to be to count as speech
+    pub threshold_db: f32,
+    /// How long speech has to last before we start sending, filters out clicks
+    pub attack_ms: u32,
+    /// How long to keep sending after the last speech frame
+    pub hangover_ms: u32,
+    /// Measure the room noise at startup instead of starting from a fixed guess
+    pub calibrate: bool,
+}
+
+impl Default for VadSettings {
+    fn default() -> Self {
+        VadSettings {
+            threshold_db: 9.0,
+            attack_ms: 40,
+            hangover_ms: 300,
+            calibrate: true,
+        }
+    }
+}
+
+impl VadSettings {
+    pub fn set_threshold(&mut self, value: &str) -> Result<(), ErrorKind> {
+        match value.parse::<f32>() {
+            Ok(db) if (0.0..=40.0).contains(&db) => {
+                self.threshold_db = db;
+                Ok(())
+            }
+            _ => Err(ErrorKind::ConfigError(format!(
+                "vad threshold must be between 0 and 40 dB, got {}",
+                value
+            ))),
+        }
+    }
+
+    pub fn set_attack(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.attack_ms = parse_ms(value, "vad attack")?;
+        Ok(())
+    }
+
+    pub fn set_hangover(&mut self, value: &str) -> Result<(), ErrorKind> {
+        self.hangover_ms = parse_ms(value, "vad hangover")?;
+        Ok(())
+    }
+}
+
+/// Decides per frame whether the microphone picks up speech, relative to a tracked noise floor
+pub struct VoiceActivityDetector {
+    settings: VadSettings,
+    channels: usize,
+    frame_ms: u32,
+    noise_floor_db: f32,
+    // quietest level of each of the last `FLOOR_BLOCKS` blocks, and of the current one
+    block_minima: VecDeque<f32>,
+    block_min_db: f32,
+    block_ms: u32,
+    active: bool,
+    // how long the current run of speech frames lasted
+    speech_ms: u32,
+    hangover_left_ms: u32,
+}
+
+impl VoiceActivityDetector {
+    pub fn new(settings: VadSettings, channels: usize, frame_ms: u8) -> Self {
+        VoiceActivityDetector {
+            settings,
+            channels,
+            frame_ms: frame_ms as u32,
+            noise_floor_db: -60.0,
+            block_minima: VecDeque::with_capacity(FLOOR_BLOCKS),
+            block_min_db: f32::INFINITY,
+            block_ms: 0,
+            active: false,
+            speech_ms: 0,
+            hangover_left_ms: 0,
+        }
+    }
+
+    /// Sets the noise floor from frames recorded while presumably nobody talked
+    pub fn calibrate(&mut self, frames: &[AudioFrame]) {
+        let mut levels: Vec<f32> = frames
+            .iter()
+            .map(|frame| energy_db(frame.samples()))
+            .collect();
+        if levels.is_empty() {
+            return;
+        }
+        levels.sort_by(f32::total_cmp);
+        let index = (levels.len() as f32 * CALIBRATION_PERCENTILE) as usize;
+        self.noise_floor_db = levels[index].max(MIN_FLOOR_DB);
+        debug!("Calibrated noise floor to {:.1} dBFS", self.noise_floor_db);
+    }
+
+    pub fn noise_floor_db(&self) -> f32 {
+        self.noise_floor_db
+    }
+
+    /// Returns true if the frame should be transmitted
+    pub fn process(&mut self, frame: &AudioFrame) -> bool {
+        let level = energy_db(frame.samples());
+        let zcr = zero_crossing_rate(frame.samples(), self.channels);
+        let loud = level > self.noise_floor_db + self.settings.threshold_db;
+        let speech = loud && zcr < MAX_SPEECH_ZCR;
+
+        if speech {
+            self.speech_ms += self.frame_ms;
+        } else {
+            // the attack needs an unbroken run, clicks between voiced frames break it
+            self.speech_ms = 0;
+            if !loud {
+                self.track_floor(level);
+            }
+        }
+        self.track_minimum(level);
+
+        // once open, any speech frame keeps us open without waiting for the attack again
+        if speech && (self.active || self.speech_ms >= self.settings.attack_ms) {
+            if !self.active {
+                debug!(
+                    "Voice detected at {:.1} dBFS, floor {:.1} dBFS",
+                    level, self.noise_floor_db
+                );
+            }
+            self.active = true;
+            self.hangover_left_ms = self.settings.hangover_ms;
+        } else if self.active {
+            self.hangover_left_ms = self.hangover_left_ms.saturating_sub(self.frame_ms);
+            if self.hangover_left_ms == 0 {
+                self.active = false;
+            }
+        }
+        self.active
+    }
+
+    /// Frames of speech needed before the detector opens, callers keep these around so
+    /// the start of a word is not cut off
+    pub fn attack_frames(&self) -> usize {
+        self.settings.attack_ms.div_ceil(self.frame_ms) as usize
+    }
+
+    fn track_floor(&mut self, level: f32) {
+        if level < self.noise_floor_db {
+            self.noise_floor_db += (level - self.noise_floor_db) * 0.1;
+        } else {
+            let rise = FLOOR_RISE_DB_PER_SEC * self.frame_ms as f32 / 1000.0;
+            self.noise_floor_db += (level - self.noise_floor_db).min(rise);
+        }
+        self.noise_floor_db = self.noise_floor_db.max(MIN_FLOOR_DB);
+    }
+
+    /// Raises the floor to the quietest level of the last `FLOOR_BLOCKS` blocks
+    fn track_minimum(&mut self, level: f32) {
+        self.block_min_db = self.block_min_db.min(level);
+        self.block_ms += self.frame_ms;
+        if self.block_ms < FLOOR_BLOCK_MS {
+            return;
+        }
+        if self.block_minima.len() == FLOOR_BLOCKS {
+            self.block_minima.pop_front();
+        }
+        self.block_minima.push_back(self.block_min_db);
+        self.block_min_db = f32::INFINITY;
+        self.block_ms = 0;
+        if self.block_minima.len() < FLOOR_BLOCKS {
+            return;
+        }
+        let minimum = self
+            .block_minima
+            .iter()
+            .copied()
+            .fold(f32::INFINITY, f32::min);
+        if minimum > self.noise_floor_db {
+            debug!(
+                "Background got louder, raising the noise floor to {:.1} dBFS",
+                minimum
+            );
+            self.noise_floor_db = minimum;
+        }
+    }
+}
+
+/// Mean power of the frame in dB relative to full scale
+pub fn energy_db(pcm: &[f32]) -> f32 {
+    if pcm.is_empty() {
+        return MIN_FLOOR_DB;
+    }
+    let power = pcm.iter().map(|s| s * s).sum::<f32>() / pcm.len() as f32;
+    10.0 * power.max(1e-10).log10()
+}
+
+/// Fraction of neighbouring samples of the first channel that change sign
+fn zero_crossing_rate(pcm: &[f32], channels: usize) -> f32 {
+    let mut crossings = 0;
+    let mut count = 0;
+    let mut previous = None;
+    for &s in pcm.iter().step_by(channels) {
+        if let Some(p) = previous
+            && (p >= 0.0) != (s >= 0.0)
+        {
+            crossings += 1;
+        }
+        previous = Some(s);
+        count += 1;
+    }
+    if count < 2 {
+        return 0.0;
+    }
+    crossings as f32 / (count - 1) as f32
+}
+
+fn parse_ms(value: &str, name: &str) -> Result<u32, ErrorKind> {
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|ms| *ms <= 5000)
+        .ok_or_else(|| {
+            ErrorKind::ConfigError(format!(
+                "{} must be between 0 and 5000 ms, got {}",
+                name, value
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::*;
+    use crate::SAMPLE_RATE;
+
+    const FRAME_MS: u8 = 20;
+    const FRAME: usize = SAMPLE_RATE as usize * FRAME_MS as usize / 1000;
+
+    fn sine(frequency: f32, amplitude: f32) -> AudioFrame {
+        let samples = (0..FRAME)
+            .map(|i| (TAU * frequency * i as f32 / SAMPLE_RATE as f32).sin() * amplitude)
+            .collect();
+        AudioFrame::from_samples(1, SAMPLE_RATE, samples)
+    }
+
+    // crosses zero on every sample
+    fn click(amplitude: f32) -> AudioFrame {
+        let samples = (0..FRAME)
+            .map(|i| if i % 2 == 0 { amplitude } else { -amplitude })
+            .collect();
+        AudioFrame::from_samples(1, SAMPLE_RATE, samples)
+    }
+
+    fn quiet_detector() -> VoiceActivityDetector {
+        let mut vad = VoiceActivityDetector::new(VadSettings::default(), 1, FRAME_MS);
+        vad.calibrate(&[sine(100.0, 0.001)]);
+        vad
+    }
+
+    #[test]
+    fn noise_step_gets_tracked() {
+        let mut vad = quiet_detector();
+        // a fan hum, loud and with few zero crossings like voiced speech
+        let hum = sine(100.0, 0.1);
+        let mut open = false;
+        for _ in 0..10 * 1000 / FRAME_MS as usize {
+            open = vad.process(&hum);
+        }
+        assert!(!open);
+        assert!((vad.noise_floor_db() - energy_db(hum.samples())).abs() < 1.0);
+    }
+
+    #[test]
+    fn keyboard_clicks_dont_open_the_gate() {
+        let mut vad = quiet_detector();
+        let voiced = sine(200.0, 0.1);
+        let clicks = click(0.1);
+        for _ in 0..50 {
+            assert!(!vad.process(&clicks));
+            assert!(!vad.process(&voiced));
+        }
+    }
+}